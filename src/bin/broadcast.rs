@@ -1,93 +1,264 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::io::{StdoutLock, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc::Receiver, Arc, Condvar, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use rand::seq::IteratorRandom;
 use rand::Rng;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use rustengan::*;
 
 fn main() -> Result<()> {
-    main_loop::<_, BroadcastNode, _, _>(())?;
+    // maelstrom的broadcast workload只发整数，BROADCAST_VALUES=json 时可以广播任意JSON值
+    let dedup = Dedup::from_env();
+    match std::env::var("BROADCAST_VALUES").as_deref() {
+        Ok("json") => main_loop::<_, BroadcastNode<JsonValue>, _, _>(dedup)?,
+        _ => main_loop::<_, BroadcastNode<usize>, _, _>(dedup)?,
+    }
     Ok(())
 }
 
-struct BroadcastNode {
+/// Anything the broadcast node can disseminate.
+trait BroadcastValue: Clone + Hash + Eq + Serialize + DeserializeOwned + Send + 'static {}
+
+impl<T> BroadcastValue for T where
+    T: Clone + Hash + Eq + Serialize + DeserializeOwned + Send + 'static
+{
+}
+
+/// An arbitrary JSON value. `serde_json::Value` isn't `Hash`, so hash its
+/// canonical serialization instead (object keys are kept sorted).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+struct JsonValue(serde_json::Value);
+
+impl Hash for JsonValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state);
+    }
+}
+
+/// How two broadcast values are recognised as the same message.
+enum Dedup {
+    /// Equal values are the same message.
+    Value,
+    /// Values carrying the same id field are the same message, whatever the rest
+    /// of their content. Values without the field fall back to `Value`.
+    Field(String),
+}
+
+impl Dedup {
+    fn from_env() -> Self {
+        match std::env::var("BROADCAST_DEDUP_FIELD") {
+            Ok(field) if !field.is_empty() => Dedup::Field(field),
+            _ => Dedup::Value,
+        }
+    }
+
+    fn key<T: Serialize>(&self, value: &T) -> Option<String> {
+        let Dedup::Field(field) = self else {
+            return None;
+        };
+        let value = serde_json::to_value(value).ok()?;
+        value.get(field).map(|id| id.to_string())
+    }
+}
+
+/// Delivery guarantee of the broadcast node, chosen with `BROADCAST_ORDER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Order {
+    /// Set union, what the maelstrom broadcast workload checks.
+    Unordered,
+    /// A message is delivered only after everything its origin had delivered
+    /// when broadcasting it.
+    Causal,
+    /// Every node delivers the same sequence: broadcasts are forwarded to a
+    /// sequencer (the smallest node id) which numbers them.
+    Total,
+}
+
+impl Order {
+    fn from_env() -> Self {
+        match std::env::var("BROADCAST_ORDER").as_deref() {
+            Ok("causal") => Order::Causal,
+            Ok("total") => Order::Total,
+            _ => Order::Unordered,
+        }
+    }
+}
+
+// 被怀疑的邻居每隔多少轮gossip试探一次，恢复后能重新收到它的消息
+const SUSPECT_PROBE_ROUNDS: u64 = 5;
+// 转发给sequencer的消息多久没收到确认就重发
+const FORWARD_RETRY: Duration = Duration::from_millis(500);
+// BROADCAST_MEMORY_STATS=1 时每隔多少轮gossip打印一次各个集合的大小
+const MEMORY_STATS_ROUNDS: u64 = 10;
+
+struct BroadcastNode<T> {
     id: usize,
     node_id: String,
-    messages: HashSet<usize>,
+    messages: HashSet<T>,
+    order: Order,
+    sequencer: String,
+    // 有序模式下每个来源已经投递到的序号，以及按投递顺序排列的消息
+    delivered: HashMap<String, u64>,
+    delivered_log: Vec<T>,
+    // 收到了但依赖还没到齐的消息
+    pending: HashMap<(String, u64), Entry<T>>,
+    // Order::Total 下转发给sequencer还没确认的消息，以及sequencer已经编号过的转发
+    next_forward: u64,
+    forwarding: BTreeMap<u64, (T, Instant)>,
+    sequenced: VersionSet,
+    dedup: Dedup,
+    // Dedup::Field 模式下已经见过的id
+    seen_ids: HashSet<String>,
+    // 每条消息用(最初收到它的节点, 该节点上的序号)标识
+    next_seq: u64,
+    have: VersionSet,
+    // have 每变一次加一，记下最近一次告诉每个邻居时的值和时间，没变化就不用发空gossip，
+    // 但隔了一个gossip间隔还要发一次当心跳，否则对方的故障检测会把安静的我们当成失联
+    have_changes: u64,
+    told: HashMap<String, (u64, Instant)>,
+    // 还有邻居不知道的消息，所有邻居都确认之后从这里压缩掉
+    log: BTreeMap<(String, u64), Logged<T>>,
+    // 压缩掉之后等待过期的消息，按压缩顺序排列
+    expiring: VecDeque<Logged<T>>,
+    expire_after: Option<Duration>,
+    memory_stats: bool,
     neighborhood: Vec<String>,
-    known: HashMap<String, HashSet<usize>>,
+    // 每个节点有哪些消息：邻居的来自它们的 have，开启过期后其他节点的由gossip转述
+    known: HashMap<String, VersionSet>,
+    // 被怀疑已经失联的邻居暂时不发，找没被怀疑的其他节点顶替，每隔几轮再试探一次
+    liveness: Liveness,
+    suspected: HashSet<String>,
+    rounds: u64,
     gossip_waker: Arc<(Mutex<bool>, Condvar)>, //msg_communicated: HashMap<usize, HashSet<usize>>,
-    gossip_delta: usize,
+    scheduler: GossipScheduler,
 }
 
-impl Node<(), Payload, InjectedPayload> for BroadcastNode {
+impl<T: BroadcastValue> Node<Dedup, Payload<T>, InjectedPayload> for BroadcastNode<T> {
     fn from_init(
-        _: (),
+        dedup: Dedup,
         init: Init,
-        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        tx: std::sync::mpsc::Sender<Event<Payload<T>, InjectedPayload>>,
     ) -> Result<Self>
     where
         Self: Sized,
     {
+        let scheduler = GossipScheduler::new(GossipConfig::from_env());
+        let interval = scheduler.interval.clone();
         let con_pair = Arc::new((Mutex::new(false), Condvar::new()));
         let clone_cvar = con_pair.clone();
         std::thread::spawn(move || loop {
             let (lock, cvar) = &*clone_cvar;
-            let need_gossip =
-                match cvar.wait_timeout(lock.lock().unwrap(), Duration::from_millis(300)) {
-                    Ok((mut g, r)) => {
-                        if *g || r.timed_out() {
-                            *g = false;
-                            true
-                        } else {
-                            false
-                        }
+            let timeout = Duration::from_millis(interval.load(Ordering::Relaxed));
+            let need_gossip = match cvar.wait_timeout(lock.lock().unwrap(), timeout) {
+                Ok((mut g, r)) => {
+                    if *g || r.timed_out() {
+                        *g = false;
+                        true
+                    } else {
+                        false
                     }
-                    Err(e) => panic!("lock poison error: {e}"),
-                };
-            if need_gossip {
-                if let Err(_) = tx.send(Event::Injected(InjectedPayload::Gossip)) {
-                    break;
                 }
+                Err(e) => panic!("lock poison error: {e}"),
+            };
+            if need_gossip && tx.send(Event::Injected(InjectedPayload::Gossip)).is_err() {
+                break;
             }
         });
+        let sequencer = init
+            .node_ids
+            .iter()
+            .min()
+            .cloned()
+            .unwrap_or_else(|| init.node_id.clone());
         Ok(BroadcastNode {
-            gossip_delta: 0,
+            scheduler,
             gossip_waker: con_pair,
             id: 1,
             node_id: init.node_id,
             messages: HashSet::new(),
+            order: Order::from_env(),
+            sequencer,
+            delivered: HashMap::new(),
+            delivered_log: Vec::new(),
+            pending: HashMap::new(),
+            next_forward: 0,
+            forwarding: BTreeMap::new(),
+            sequenced: VersionSet::default(),
+            dedup,
+            seen_ids: HashSet::new(),
+            next_seq: 0,
+            have: VersionSet::default(),
+            have_changes: 0,
+            told: HashMap::new(),
+            log: BTreeMap::new(),
+            expiring: VecDeque::new(),
+            expire_after: match env_or("BROADCAST_EXPIRE_MS", 0u64) {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            memory_stats: env_or("BROADCAST_MEMORY_STATS", 0u8) != 0,
             known: init
                 .node_ids
                 .into_iter()
-                .map(|nid| (nid, HashSet::new()))
+                .map(|nid| (nid, VersionSet::default()))
                 .collect(),
             neighborhood: Default::default(),
+            liveness: init.liveness,
+            suspected: HashSet::new(),
+            rounds: 0,
             //     msg_communicated: HashMap::new(),
         })
     }
     fn step(
         &mut self,
-        input: Event<Payload, InjectedPayload>,
+        input: Event<Payload<T>, InjectedPayload>,
         output: &mut StdoutLock,
-        _: &Receiver<Event<Payload, InjectedPayload>>,
+        _: &Receiver<Event<Payload<T>, InjectedPayload>>,
     ) -> Result<()> {
         match input {
             Event::Message(input) => {
                 let mut reply = input.into_reply(Some(&mut self.id));
                 match reply.body.payload {
                     Payload::Broadcast { message } => {
-                        self.messages.insert(message);
+                        if self.order == Order::Total && self.node_id != self.sequencer {
+                            let forward_id = self.next_forward;
+                            self.next_forward += 1;
+                            self.forwarding
+                                .insert(forward_id, (message.clone(), Instant::now()));
+                            self.forward(forward_id, message, output)?;
+                        } else {
+                            self.originate(message);
+                        }
                         reply.body.payload = Payload::BroadcastOk;
                         reply.send(output)?;
                     }
+                    Payload::Sequence {
+                        message,
+                        forward_id,
+                    } => {
+                        // 重发的转发只编号一次
+                        if self.sequenced.insert(&reply.dst, forward_id) {
+                            self.originate(message);
+                        }
+                        reply.body.payload = Payload::SequenceOk { forward_id };
+                        reply.send(output)?;
+                    }
+                    Payload::SequenceOk { forward_id } => {
+                        self.forwarding.remove(&forward_id);
+                    }
                     Payload::Read => {
-                        reply.body.payload = Payload::ReadOk {
-                            messages: self.messages.iter().map(Clone::clone).collect(),
+                        let messages = match self.order {
+                            Order::Unordered => self.messages.iter().map(Clone::clone).collect(),
+                            Order::Causal | Order::Total => self.delivered_log.clone(),
                         };
+                        reply.body.payload = Payload::ReadOk { messages };
                         reply.send(output)?;
                     }
                     Payload::Topology { mut topology } => {
@@ -114,20 +285,35 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                         //eprintln!("neighborhood: {:?}", self.neighborhood);
                         reply.send(output)?;
                     }
-                    Payload::Gossip { seen } => {
+                    Payload::Gossip {
+                        entries,
+                        have,
+                        acks,
+                        sent_at,
+                    } => {
                         // eprintln!("gossip {}", reply.dst);
-                        self.known
+                        self.scheduler.record_latency(sent_at);
+                        for (node, has) in &acks {
+                            if let Some(known) = self.known.get_mut(node) {
+                                known.merge(has);
+                            }
+                        }
+                        let known = self
+                            .known
                             .get_mut(&reply.dst)
-                            .expect("got gossip from unknown node")
-                            .extend(seen.iter().copied());
-                        let before_msgs_length = self.messages.len();
-                        self.messages.extend(seen);
-                        // eprintln!("message length: {}", self.messages.len());
-                        if self.messages.len() - before_msgs_length >= self.gossip_delta {
-                            self.gossip_delta = self.messages.len() - before_msgs_length;
-                            *self.gossip_waker.0.lock().unwrap() = true;
-                            self.gossip_waker.1.notify_one();
+                            .expect("got gossip from unknown node");
+                        known.merge(&have);
+                        for entry in &entries {
+                            known.insert(&entry.origin, entry.seq);
+                        }
+                        let mut new_msgs = 0;
+                        for entry in entries {
+                            if self.accept(entry) {
+                                new_msgs += 1;
+                            }
                         }
+                        // eprintln!("message length: {}", self.messages.len());
+                        self.record_arrivals(new_msgs);
                     }
                     Payload::GossipOk
                     | Payload::BroadcastOk
@@ -135,34 +321,168 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                     | Payload::ReadOk { .. } => (),
                 }
             }
-            Event::Injected(InjectedPayload::Gossip) => self.gossip(output)?,
+            Event::Injected(InjectedPayload::Gossip) => {
+                self.retry_forwards(output)?;
+                self.gossip(output)?;
+            }
             Event::EOF => (),
         }
         Ok(())
     }
 }
 
-impl BroadcastNode {
+impl<T: BroadcastValue> BroadcastNode<T> {
+    fn originate(&mut self, message: T) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let entry = Entry {
+            origin: self.node_id.clone(),
+            seq,
+            value: message,
+            deps: (self.order == Order::Causal).then(|| self.delivered.clone()),
+        };
+        if self.accept(entry) {
+            self.record_arrivals(1);
+        }
+    }
+
+    fn accept(&mut self, entry: Entry<T>) -> bool {
+        if !self.have.insert(&entry.origin, entry.seq) {
+            return false;
+        }
+        self.have_changes += 1;
+        let dot = (entry.origin.clone(), entry.seq);
+        if self.order == Order::Unordered {
+            // 内容重复的消息只记下(origin, seq)，不用再转发
+            if !self.insert(entry.value.clone()) {
+                return false;
+            }
+        } else {
+            // 有序模式下重复的消息也要转发，否则别的节点等不到这个序号，后面的消息都投递不了
+            self.pending.insert(dot.clone(), entry.clone());
+            self.deliver_ready();
+        }
+        self.log.insert(
+            dot,
+            Logged {
+                entry,
+                received: Instant::now(),
+            },
+        );
+        true
+    }
+
+    fn deliverable(&self, entry: &Entry<T>) -> bool {
+        let delivered = |origin: &str| self.delivered.get(origin).copied().unwrap_or(0);
+        delivered(&entry.origin) == entry.seq
+            && entry
+                .deps
+                .iter()
+                .flatten()
+                .all(|(origin, &count)| origin == &entry.origin || delivered(origin) >= count)
+    }
+
+    fn deliver_ready(&mut self) {
+        loop {
+            let ready: Vec<_> = self
+                .pending
+                .iter()
+                .filter(|(_, entry)| self.deliverable(entry))
+                .map(|(dot, _)| dot.clone())
+                .collect();
+            if ready.is_empty() {
+                return;
+            }
+            for dot in ready {
+                let entry = self.pending.remove(&dot).unwrap();
+                *self.delivered.entry(entry.origin).or_default() += 1;
+                if self.insert(entry.value.clone()) {
+                    self.delivered_log.push(entry.value);
+                }
+            }
+        }
+    }
+
+    fn forward(&mut self, forward_id: u64, message: T, output: &mut impl Write) -> Result<()> {
+        Message {
+            src: self.node_id.clone(),
+            dst: self.sequencer.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Sequence {
+                    message,
+                    forward_id,
+                },
+            },
+        }
+        .send(output)
+    }
+
+    fn retry_forwards(&mut self, output: &mut impl Write) -> Result<()> {
+        let expired: Vec<_> = self
+            .forwarding
+            .iter_mut()
+            .filter(|(_, (_, sent))| sent.elapsed() >= FORWARD_RETRY)
+            .map(|(&forward_id, (message, sent))| {
+                *sent = Instant::now();
+                (forward_id, message.clone())
+            })
+            .collect();
+        for (forward_id, message) in expired {
+            self.forward(forward_id, message, output)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, message: T) -> bool {
+        if let Some(id) = self.dedup.key(&message) {
+            if !self.seen_ids.insert(id) {
+                return false;
+            }
+        }
+        self.messages.insert(message)
+    }
+
+    // 积压攒够一批就提前唤醒gossip线程，不用等到间隔超时
+    fn record_arrivals(&mut self, n: usize) {
+        if self.scheduler.record_arrivals(n) {
+            *self.gossip_waker.0.lock().unwrap() = true;
+            self.gossip_waker.1.notify_one();
+        }
+    }
+
     fn gossip(&mut self, output: &mut impl Write) -> Result<()> {
-        for n in &self.neighborhood {
+        let mut backlog = 0;
+        let batch = self.scheduler.batch;
+        let heartbeat = Duration::from_millis(self.scheduler.interval.load(Ordering::Relaxed));
+        let acks = self.acks();
+        let mut rng = rand::thread_rng();
+        for n in &self.gossip_targets() {
             let knows_to_n = &self.known[n];
-            let (already_known, mut notify_of): (HashSet<_>, HashSet<_>) = self
-                .messages
+            let unknown: Vec<_> = self
+                .log
                 .iter()
-                .copied()
-                .partition(|m| knows_to_n.contains(m));
-            // eprintln!("notify of {}/{}", notify_of.len(), self.messages.len());
-            // if we know that n knows m, we don't tell n that we know m
-            // send us m for all eternity, so
-            // include a couple of extra messages to let them know that we know they know
-            // 邻居较少，而且网络带宽费贵的情况下，就增加一次传输携带大数据包，当已知数据的量很大的时候，最大附带1/3的数据，当数据量小的时候就全部携带,最多带30条数据
-            let mut rng = rand::thread_rng();
-            notify_of.extend(already_known.iter().filter(|_| {
-                rng.gen_ratio(
-                    30.min(already_known.len()).max(already_known.len() / 3) as u32,
-                    already_known.len() as u32,
-                )
-            }));
+                .filter(|((origin, seq), _)| !knows_to_n.contains(origin, *seq))
+                .collect();
+            backlog = backlog.max(unknown.len());
+            // 每次最多发一批，随机抽取避免总是重复发送同一批而饿死其他消息
+            let entries: Vec<_> = unknown
+                .into_iter()
+                .choose_multiple(&mut rng, batch)
+                .into_iter()
+                .map(|(_, logged)| logged.entry.clone())
+                .collect();
+            // 不再随机附带对方已知的消息来告诉它"我们知道它知道"，每次gossip都带上自己的
+            // have 摘要，只有新消息也没有 have 变化、而且刚发过时才不用发
+            let idle = self.told.get(n).is_some_and(|&(changes, at)| {
+                changes == self.have_changes && at.elapsed() < heartbeat / 2
+            });
+            if entries.is_empty() && idle {
+                continue;
+            }
+            self.told
+                .insert(n.clone(), (self.have_changes, Instant::now()));
 
             Message {
                 src: self.node_id.clone(),
@@ -170,26 +490,365 @@ impl BroadcastNode {
                 body: Body {
                     id: None,
                     in_reply_to: None,
-                    payload: Payload::Gossip { seen: notify_of },
+                    payload: Payload::Gossip {
+                        entries,
+                        have: self.have.clone(),
+                        acks: acks.clone(),
+                        sent_at: unix_millis(),
+                    },
                 },
             }
             .send(&mut *output)?;
         }
+        self.compact();
+        self.expire();
+        self.scheduler.adapt(backlog);
+        if self.memory_stats && self.rounds.is_multiple_of(MEMORY_STATS_ROUNDS) {
+            eprintln!(
+                "broadcast memory: messages={} log={} expiring={} known={}",
+                self.messages.len(),
+                self.log.len(),
+                self.expiring.len(),
+                self.known.values().map(VersionSet::ranges).sum::<usize>()
+            );
+        }
         Ok(())
     }
+
+    fn gossip_targets(&mut self) -> Vec<String> {
+        self.rounds += 1;
+        let mut targets = Vec::new();
+        let mut suspected = 0;
+        for n in &self.neighborhood {
+            if !self.liveness.is_suspected(n) {
+                if self.suspected.remove(n) {
+                    eprintln!("neighbor {n} is reachable again");
+                }
+                targets.push(n.clone());
+                continue;
+            }
+            if self.suspected.insert(n.clone()) {
+                eprintln!(
+                    "neighbor {n} suspected (phi={:.1}), routing around it",
+                    self.liveness.phi(n)
+                );
+            }
+            suspected += 1;
+            if self.rounds.is_multiple_of(SUSPECT_PROBE_ROUNDS) {
+                targets.push(n.clone());
+            }
+        }
+        if suspected > 0 {
+            let mut rng = rand::thread_rng();
+            targets.extend(
+                self.known
+                    .keys()
+                    .filter(|n| {
+                        *n != &self.node_id
+                            && !self.neighborhood.contains(n)
+                            && !self.liveness.is_suspected(n)
+                    })
+                    .cloned()
+                    .choose_multiple(&mut rng, suspected),
+            );
+        }
+        targets
+    }
+
+    // 所有邻居都确认过的消息不用再发，从log里删掉；邻居之外的节点由它们各自的邻居负责
+    fn compact(&mut self) {
+        if self.neighborhood.is_empty() {
+            return;
+        }
+        let acked: Vec<_> = self
+            .log
+            .keys()
+            .filter(|(origin, seq)| {
+                self.neighborhood
+                    .iter()
+                    .all(|n| self.known[n].contains(origin, *seq))
+            })
+            .cloned()
+            .collect();
+        for dot in acked {
+            let logged = self.log.remove(&dot).expect("acked message must be in log");
+            if self.expire_after.is_some() {
+                self.expiring.push_back(logged);
+            }
+        }
+    }
+
+    // 开启过期后把自己知道的每个节点有哪些消息转述给邻居，这样不相邻的节点也能知道
+    // 一条消息是不是所有节点都收到了
+    fn acks(&self) -> HashMap<String, VersionSet> {
+        if self.expire_after.is_none() {
+            return HashMap::new();
+        }
+        let mut acks: HashMap<_, _> = self
+            .known
+            .iter()
+            .filter(|(n, _)| **n != self.node_id)
+            .map(|(n, has)| (n.clone(), has.clone()))
+            .collect();
+        acks.insert(self.node_id.clone(), self.have.clone());
+        acks
+    }
+
+    // 开启 BROADCAST_EXPIRE_MS 后，所有节点都确认过且足够老的消息也从 read 结果里删掉，内存只和
+    // 过期窗口内的消息量有关。有节点连不上时它还没确认的消息都留着
+    fn expire(&mut self) {
+        let Some(expire_after) = self.expire_after else {
+            return;
+        };
+        while let Some(logged) = self.expiring.front() {
+            if logged.received.elapsed() < expire_after {
+                break;
+            }
+            let (origin, seq) = (&logged.entry.origin, logged.entry.seq);
+            let everyone = self
+                .known
+                .iter()
+                .all(|(n, has)| *n == self.node_id || has.contains(origin, seq));
+            if !everyone {
+                break;
+            }
+            let logged = self.expiring.pop_front().unwrap();
+            let value = logged.entry.value;
+            if let Some(id) = self.dedup.key(&value) {
+                self.seen_ids.remove(&id);
+            }
+            if self.order != Order::Unordered {
+                self.delivered_log.retain(|v| v != &value);
+            }
+            self.messages.remove(&value);
+        }
+    }
+}
+
+struct Logged<T> {
+    entry: Entry<T>,
+    received: Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "T: BroadcastValue")]
+struct Entry<T> {
+    origin: String,
+    seq: u64,
+    value: T,
+    // Order::Causal 下，origin 广播这条消息时已经投递的每个来源的消息数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deps: Option<HashMap<String, u64>>,
+}
+
+/// Sequence numbers stored as disjoint half-open ranges `start -> end`, so a
+/// peer that has seen everything up to n costs one entry instead of n.
+// 序列化成 [[start, end], ...]: payload 是 flatten 进 body 的，整数做 map key 反序列化不了
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(u64, u64)>", into = "Vec<(u64, u64)>")]
+struct IntervalSet(BTreeMap<u64, u64>);
+
+impl From<Vec<(u64, u64)>> for IntervalSet {
+    fn from(ranges: Vec<(u64, u64)>) -> Self {
+        let mut set = IntervalSet::default();
+        for (start, end) in ranges {
+            set.insert_range(start, end);
+        }
+        set
+    }
+}
+
+impl From<IntervalSet> for Vec<(u64, u64)> {
+    fn from(set: IntervalSet) -> Self {
+        set.0.into_iter().collect()
+    }
+}
+
+impl IntervalSet {
+    fn contains(&self, seq: u64) -> bool {
+        self.0
+            .range(..=seq)
+            .next_back()
+            .is_some_and(|(_, &end)| seq < end)
+    }
+
+    fn insert(&mut self, seq: u64) -> bool {
+        if self.contains(seq) {
+            return false;
+        }
+        self.insert_range(seq, seq + 1);
+        true
+    }
+
+    fn insert_range(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+        if let Some((&s, &e)) = self.0.range(..=start).next_back() {
+            if e >= start {
+                start = s;
+                end = end.max(e);
+            }
+        }
+        let touching: Vec<_> = self.0.range(start..=end).map(|(&s, _)| s).collect();
+        for s in touching {
+            end = end.max(self.0.remove(&s).unwrap());
+        }
+        self.0.insert(start, end);
+    }
+}
+
+/// Which `(origin, seq)` messages a node has, one `IntervalSet` per origin.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct VersionSet(HashMap<String, IntervalSet>);
+
+impl VersionSet {
+    fn contains(&self, origin: &str, seq: u64) -> bool {
+        self.0.get(origin).is_some_and(|s| s.contains(seq))
+    }
+
+    fn insert(&mut self, origin: &str, seq: u64) -> bool {
+        if let Some(set) = self.0.get_mut(origin) {
+            return set.insert(seq);
+        }
+        self.0.entry(origin.to_string()).or_default().insert(seq)
+    }
+
+    /// Number of stored ranges over all origins, what the set costs in memory.
+    fn ranges(&self) -> usize {
+        self.0.values().map(|set| set.0.len()).sum()
+    }
+
+    fn merge(&mut self, other: &VersionSet) {
+        for (origin, ranges) in &other.0 {
+            let set = self.0.entry(origin.clone()).or_default();
+            for (&start, &end) in &ranges.0 {
+                set.insert_range(start, end);
+            }
+        }
+    }
+}
+
+/// Bounds for the adaptive gossip scheduler, configurable through the
+/// environment so the efficient-broadcast targets can be tuned without a rebuild.
+struct GossipConfig {
+    min_interval: u64,
+    max_interval: u64,
+    min_batch: usize,
+    max_batch: usize,
+}
+
+impl GossipConfig {
+    fn from_env() -> Self {
+        let min_interval = env_or("GOSSIP_MIN_INTERVAL_MS", 100u64).max(1);
+        let max_interval = env_or("GOSSIP_MAX_INTERVAL_MS", 500u64).max(min_interval);
+        let min_batch = env_or("GOSSIP_MIN_BATCH", 8usize).max(1);
+        let max_batch = env_or("GOSSIP_MAX_BATCH", 256usize).max(min_batch);
+        GossipConfig {
+            min_interval,
+            max_interval,
+            min_batch,
+            max_batch,
+        }
+    }
+}
+
+// 新样本在滑动平均里的权重
+const EWMA_ALPHA: f64 = 0.3;
+const INITIAL_INTERVAL_MS: u64 = 300;
+
+/// Picks the gossip interval and per-neighbor batch size from the observed
+/// arrival rate of new messages, the backlog of messages some neighbor hasn't
+/// seen yet and the measured one-way network latency.
+struct GossipScheduler {
+    config: GossipConfig,
+    // 毫秒，和gossip线程共享
+    interval: Arc<AtomicU64>,
+    batch: usize,
+    // 新消息到达速率(条/秒)和网络延迟(毫秒)的滑动平均
+    arrival_rate: f64,
+    latency: f64,
+    arrivals: usize,
+    last_round: Instant,
+}
+
+impl GossipScheduler {
+    fn new(config: GossipConfig) -> Self {
+        let interval = INITIAL_INTERVAL_MS.clamp(config.min_interval, config.max_interval);
+        GossipScheduler {
+            interval: Arc::new(AtomicU64::new(interval)),
+            batch: config.min_batch,
+            config,
+            arrival_rate: 0.0,
+            latency: 0.0,
+            arrivals: 0,
+            last_round: Instant::now(),
+        }
+    }
+
+    /// Returns true once a full batch has piled up since the last round.
+    fn record_arrivals(&mut self, n: usize) -> bool {
+        self.arrivals += n;
+        n > 0 && self.arrivals >= self.batch
+    }
+
+    fn record_latency(&mut self, sent_at: u64) {
+        if sent_at == 0 {
+            return;
+        }
+        let sample = unix_millis().saturating_sub(sent_at) as f64;
+        self.latency = EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * self.latency;
+    }
+
+    fn adapt(&mut self, backlog: usize) {
+        let elapsed = self.last_round.elapsed().as_secs_f64().max(0.001);
+        self.last_round = Instant::now();
+        let rate = self.arrivals as f64 / elapsed;
+        self.arrivals = 0;
+        self.arrival_rate = EWMA_ALPHA * rate + (1.0 - EWMA_ALPHA) * self.arrival_rate;
+
+        let current = self.interval.load(Ordering::Relaxed);
+        // 一批大约装下一个间隔内到达的新消息，积压更多时放大批次
+        let expected = (self.arrival_rate * current as f64 / 1000.0).ceil() as usize;
+        let batch = expected
+            .max(backlog)
+            .clamp(self.config.min_batch, self.config.max_batch);
+        let interval = if backlog > batch {
+            // 最大批次都装不下积压，邻居落后了，缩短间隔追赶
+            current / 2
+        } else if self.arrival_rate >= 1.0 {
+            // 攒满一批再发，但不短于网络延迟，否则只是重复发送对方还没来得及确认的消息
+            ((batch as f64 / self.arrival_rate * 1000.0) as u64).max(self.latency as u64)
+        } else {
+            // 没什么新消息，逐步退避
+            current + current / 2
+        }
+        .clamp(self.config.min_interval, self.config.max_interval);
+
+        if interval != current || batch != self.batch {
+            eprintln!(
+                "gossip scheduler: rate={:.1}/s latency={:.0}ms backlog={} => interval={}ms batch={}",
+                self.arrival_rate, self.latency, backlog, interval, batch
+            );
+        }
+        self.interval.store(interval, Ordering::Relaxed);
+        self.batch = batch;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+#[serde(bound = "T: BroadcastValue")]
+enum Payload<T> {
     Broadcast {
-        message: usize,
+        message: T,
     },
     BroadcastOk,
     Read,
     ReadOk {
-        messages: HashSet<usize>,
+        messages: Vec<T>,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
@@ -197,9 +856,22 @@ enum Payload {
     TopologyOk,
 
     Gossip {
-        seen: HashSet<usize>,
+        entries: Vec<Entry<T>>,
+        have: VersionSet,
+        // 开启过期时，发送方知道的每个节点的 have
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        acks: HashMap<String, VersionSet>,
+        #[serde(default)]
+        sent_at: u64,
     },
     GossipOk,
+    Sequence {
+        message: T,
+        forward_id: u64,
+    },
+    SequenceOk {
+        forward_id: u64,
+    },
 }
 
 enum InjectedPayload {
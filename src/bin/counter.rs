@@ -1,22 +1,31 @@
+use std::collections::HashMap;
 use std::io::StdoutLock;
+use std::path::PathBuf;
 use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+use rustengan::crdt::PNCounter;
 use rustengan::*;
 
 const GLOBAL_KEY: &str = "Counter";
 
 fn main() -> Result<()> {
-    main_loop::<_, CounterNode<SeqKv>, _, _>(())?;
+    match std::env::var("COUNTER_MODE").as_deref() {
+        Ok("crdt") => main_loop::<_, GossipCounterNode, _, _>(())?,
+        Ok("buffered") => main_loop::<_, BufferedCounterNode, _, _>(())?,
+        Ok("sharded") => main_loop::<_, CounterNode<SeqKv>, _, _>(Layout::Sharded)?,
+        _ => main_loop::<_, CounterNode<SeqKv>, _, _>(Layout::Global)?,
+    }
     Ok(())
 }
 
 struct SeqKv {}
 
 impl KV for SeqKv {
-    type Value = u64;
+    type Value = i64;
     type Payload = Payload;
     fn read(&mut self, rt: &mut Runtime<'_, '_, Self::Payload>, key: &str) -> Result<Self::Value> {
         let payload = Payload::KvRead {
@@ -30,12 +39,8 @@ impl KV for SeqKv {
         };
         rt.in_reply_to = input.body.id;
         match input.body.payload {
-            Payload::ReadOk { value } => {
-                return Ok(value);
-            }
-            Payload::Error { code, text } => {
-                return Err(GanError::Rpc { code, text });
-            }
+            Payload::ReadOk { value } => Ok(value),
+            Payload::Error { code, text } => Err(GanError::Rpc { code, text }),
             _ => Err(GanError::Normal("should not be other payload".to_string())),
         }
     }
@@ -89,27 +94,61 @@ impl KV for SeqKv {
                 if code == 22 {
                     return Err(GanError::PreconditionFailed);
                 }
-                return Err(GanError::Rpc { code, text });
+                Err(GanError::Rpc { code, text })
             }
             _ => Err(GanError::Normal("should not be other payload".to_string())),
         }
     }
 }
 
+/// Where the counter lives in seq-kv.
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    /// Every node CASes the single `GLOBAL_KEY`.
+    Global,
+    /// Every node only CASes its own `counter-<node>` key, so the only
+    /// contention left is with itself; reads sum the keys of all nodes.
+    Sharded,
+}
+
 struct CounterNode<K: KV> {
     id: usize,
     node_id: String,
+    layout: Layout,
+    // add 写的key，以及 read 要加起来的key
+    own_key: String,
+    read_keys: Vec<String>,
     kv: K,
+    retry: Retry,
+    adds: u64,
+    cas_retries: u64,
 }
-impl Node<(), Payload> for CounterNode<SeqKv> {
-    fn from_init(_: (), init: Init, _: std::sync::mpsc::Sender<Event<Payload>>) -> Result<Self>
+impl Node<Layout, Payload> for CounterNode<SeqKv> {
+    fn from_init(
+        layout: Layout,
+        init: Init,
+        _: std::sync::mpsc::Sender<Event<Payload>>,
+    ) -> Result<Self>
     where
         Self: Sized,
     {
+        let (own_key, read_keys) = match layout {
+            Layout::Global => (GLOBAL_KEY.to_string(), vec![GLOBAL_KEY.to_string()]),
+            Layout::Sharded => (
+                shard_key(&init.node_id),
+                init.node_ids.iter().map(|n| shard_key(n)).collect(),
+            ),
+        };
         Ok(Self {
             id: 1,
             node_id: init.node_id,
+            layout,
+            own_key,
+            read_keys,
             kv: SeqKv {},
+            retry: Retry::from_env(),
+            adds: 0,
+            cas_retries: 0,
         })
     }
     fn step(
@@ -130,13 +169,24 @@ impl Node<(), Payload> for CounterNode<SeqKv> {
         };
         match input.body.payload {
             Payload::Add { delta } => {
-                add_delta(&mut self.kv, delta, rt)?;
+                let added = add_delta(&mut self.kv, &self.own_key, delta, &self.retry, rt);
                 let mut reply = input.into_reply(Some(&mut self.id));
-                reply.body.payload = Payload::AddOk;
+                reply.body.payload = match added {
+                    Ok(retries) => {
+                        self.record_retries(retries);
+                        Payload::AddOk
+                    }
+                    // 重试预算用完了，告诉客户端这次 add 没有生效，可以重试
+                    Err(e @ GanError::RetriesExhausted { .. }) => Payload::Error {
+                        code: 11,
+                        text: e.to_string(),
+                    },
+                    Err(e) => return Err(e),
+                };
                 reply.send(output)?;
             }
             Payload::Read => {
-                let value = read(&mut self.kv, rt)?;
+                let value = read(&mut self.kv, &self.read_keys, rt)?;
                 let mut reply = input.into_reply(Some(&mut self.id));
                 reply.body.payload = Payload::ReadOk { value };
                 reply.send(output)?;
@@ -148,7 +198,8 @@ impl Node<(), Payload> for CounterNode<SeqKv> {
             | Payload::Write { .. }
             | Payload::Cas { .. }
             | Payload::AddOk
-            | Payload::KvRead { .. } => {
+            | Payload::KvRead { .. }
+            | Payload::Gossip { .. } => {
                 return Err(GanError::Normal(
                     "we should never receive generate_ok".to_string(),
                 ))
@@ -158,57 +209,572 @@ impl Node<(), Payload> for CounterNode<SeqKv> {
     }
 }
 
-fn add_delta(kv: &mut SeqKv, delta: u64, rt: Runtime<Payload>) -> Result<()> {
-    if delta == 0 {
-        return Ok(());
-    }
-    let mut rt = rt;
-    loop {
-        let (old, new_rt) = read_inner(kv, rt)?;
-        rt = new_rt;
-        match kv.compare_exchange(&mut rt, GLOBAL_KEY, old, old + delta, true) {
-            Ok(_) => return Ok(()),
-            Err(GanError::PreconditionFailed) => (),
-            Err(e) => return Err(e),
+impl<K: KV> CounterNode<K> {
+    // 每100次add打一次CAS重试统计，用来比较两种布局的冲突
+    fn record_retries(&mut self, retries: u64) {
+        self.adds += 1;
+        self.cas_retries += retries;
+        if self.adds.is_multiple_of(100) {
+            eprintln!(
+                "counter {:?}: {} adds, {} cas retries ({:.2} per add)",
+                self.layout,
+                self.adds,
+                self.cas_retries,
+                self.cas_retries as f64 / self.adds as f64
+            );
         }
     }
 }
 
-fn read(kv: &mut SeqKv, mut rt: Runtime<Payload>) -> Result<u64> {
+fn shard_key(node_id: &str) -> String {
+    format!("{}-{}", GLOBAL_KEY.to_lowercase(), node_id)
+}
+
+/// CAS `delta` onto `key`, returning how many attempts hit a concurrent update.
+/// Gives up with `GanError::RetriesExhausted` once `retry` runs out.
+fn add_delta(
+    kv: &mut SeqKv,
+    key: &str,
+    delta: i64,
+    retry: &Retry,
+    mut rt: Runtime<Payload>,
+) -> Result<u64> {
+    if delta == 0 {
+        return Ok(0);
+    }
+    retry.run(
+        |attempt| {
+            let old = read_inner(kv, key, &mut rt)?;
+            kv.compare_exchange(&mut rt, key, old, old + delta, true)?;
+            Ok(attempt as u64)
+        },
+        |e| matches!(e, GanError::PreconditionFailed),
+    )
+}
+
+fn read(kv: &mut SeqKv, keys: &[String], mut rt: Runtime<Payload>) -> Result<i64> {
     // Do a "sync" to read latest values. See https://github.com/jepsen-io/maelstrom/issues/39#issuecomment-1445414521
     // Looks like seq-kv is sequential across all keys.
     let mut rng = rand::thread_rng();
-    kv.write(&mut rt, "sync".to_string(), rng.gen_range(0..1000_000_000))?;
-    Ok(read_inner(kv, rt)?.0)
+    kv.write(&mut rt, "sync".to_string(), rng.gen_range(0..1_000_000_000))?;
+    let mut sum = 0;
+    for key in keys {
+        sum += read_inner(kv, key, &mut rt)?;
+    }
+    Ok(sum)
 }
 
-fn read_inner<'a, 'stdout>(
-    kv: &mut SeqKv,
-    mut rt: Runtime<'a, 'stdout, Payload>,
-) -> Result<(u64, Runtime<'a, 'stdout, Payload>)> {
-    match kv.read(&mut rt, GLOBAL_KEY) {
-        Ok(g) => Ok((g, rt)),
-        // key not exist
-        Err(GanError::Rpc { code, .. }) if code == 20 => {
-            let _ = kv.write(&mut rt, GLOBAL_KEY.to_string(), 0)?;
-            return Ok((0, rt));
-        }
+fn read_inner(kv: &mut SeqKv, key: &str, rt: &mut Runtime<Payload>) -> Result<i64> {
+    match kv.read(rt, key) {
+        Ok(g) => Ok(g),
+        // key not exist. 不写0进去：别的节点可能正好创建了这个key，CAS 的
+        // create_if_not_exists 会自己建
+        Err(GanError::Rpc { code: 20, .. }) => Ok(0),
         Err(e) => Err(e),
     }
 }
 
+/// Counter built on a PN-Counter CRDT, i.e. two G-Counters for increments and
+/// decrements: every node only grows its own slots and the per-node counts are
+/// merged by max through periodic gossip, so an `add` never waits on seq-kv
+/// and partitions only delay convergence. Each round ships only the slots
+/// changed since the last one, and every `COUNTER_FULL_GOSSIP_EVERY` rounds
+/// the whole counter.
+/// With `COUNTER_PERSIST=1` each node also saves its own slots to seq-kv and
+/// restores them after a restart. Local adds are then only gossiped once
+/// seq-kv has them, so no peer can know a larger own slot than the one the
+/// node restores.
+struct GossipCounterNode {
+    id: usize,
+    node_id: String,
+    counter: Gossiper<PNCounter>,
+    persist: bool,
+    persisted: HashMap<Slot, Persisted>,
+    // 还没回复的seq-kv请求是哪个slot的
+    inflight: HashMap<usize, Slot>,
+    write_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Slot {
+    Increments,
+    Decrements,
+}
+
+const SLOTS: [Slot; 2] = [Slot::Increments, Slot::Decrements];
+
+#[derive(Default)]
+struct Persisted {
+    // 从seq-kv恢复之前不写自己的slot，免得用重启后的小值覆盖
+    recovered: bool,
+    // 还没写进seq-kv的本地add，写成功之前不进counter，也就不会gossip出去
+    pending: u64,
+    // 正在写的值，请求的msg_id和发出的时间
+    writing: Option<(u64, usize, Instant)>,
+}
+
+impl Node<(), Payload, InjectedPayload> for GossipCounterNode {
+    fn from_init(
+        _: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let interval = Duration::from_millis(env_or("COUNTER_GOSSIP_MS", 200));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if tx.send(Event::Injected(InjectedPayload::Tick)).is_err() {
+                break;
+            }
+        });
+        let persist = env_or("COUNTER_PERSIST", 0u8) != 0;
+        Ok(Self {
+            id: 1,
+            counter: Gossiper::new(&init.node_id, &init.node_ids)
+                .full_every(env_or("COUNTER_FULL_GOSSIP_EVERY", 10)),
+            node_id: init.node_id,
+            persist,
+            persisted: SLOTS
+                .into_iter()
+                .map(|slot| {
+                    let persisted = Persisted {
+                        recovered: !persist,
+                        ..Default::default()
+                    };
+                    (slot, persisted)
+                })
+                .collect(),
+            inflight: HashMap::new(),
+            write_timeout: Duration::from_millis(env_or("COUNTER_PERSIST_TIMEOUT_MS", 1000)),
+        })
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut StdoutLock,
+        _: &Receiver<Event<Payload, InjectedPayload>>,
+    ) -> Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Tick) => return self.gossip(output),
+            Event::EOF => return Ok(()),
+        };
+        let slot = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.inflight.remove(&id));
+        let mut reply = input.into_reply(Some(&mut self.id));
+        match reply.body.payload {
+            Payload::Add { delta } => {
+                if self.persist {
+                    let slot = if delta >= 0 {
+                        Slot::Increments
+                    } else {
+                        Slot::Decrements
+                    };
+                    self.persisted.get_mut(&slot).unwrap().pending += delta.unsigned_abs();
+                } else {
+                    let node_id = &self.node_id;
+                    self.counter.update(|counter| counter.add(node_id, delta));
+                }
+                reply.body.payload = Payload::AddOk;
+                reply.send(output)?;
+            }
+            Payload::Read => {
+                let pending = |slot| self.persisted[&slot].pending as i64;
+                reply.body.payload = Payload::ReadOk {
+                    value: self.counter.state().value() + pending(Slot::Increments)
+                        - pending(Slot::Decrements),
+                };
+                reply.send(output)?;
+            }
+            Payload::Gossip { state } => {
+                // 恢复之前别人手里自己slot的旧值不能合并进来，否则恢复时会重复计算。
+                // 丢掉的部分下一轮全量gossip会再发过来
+                if self.persisted.values().all(|p| p.recovered) {
+                    self.counter.merge(&state);
+                }
+            }
+            // seq-kv 返回的自己slot的持久化值
+            Payload::ReadOk { value } => {
+                if let Some(slot) = slot {
+                    self.recover(slot, value as u64);
+                }
+            }
+            Payload::Error { code: 20, .. } => {
+                if let Some(slot) = slot {
+                    self.recover(slot, 0);
+                }
+            }
+            Payload::Error { code, text } => {
+                eprintln!("counter persist error({code}): {text}");
+                // 写失败了，下一轮重写这个slot
+                if let Some(slot) = slot {
+                    self.persisted.get_mut(&slot).unwrap().writing = None;
+                }
+            }
+            Payload::WriteOk => {
+                if let Some(slot) = slot {
+                    self.persisted(slot);
+                }
+            }
+            Payload::CasOk
+            | Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::AddOk
+            | Payload::KvRead { .. } => {
+                return Err(GanError::Normal(
+                    "should not exist invalid response".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GossipCounterNode {
+    fn slot_key(&self, slot: Slot) -> String {
+        let suffix = match slot {
+            Slot::Increments => "inc",
+            Slot::Decrements => "dec",
+        };
+        format!("{}-{}-{}", GLOBAL_KEY.to_lowercase(), self.node_id, suffix)
+    }
+
+    fn own(&self, slot: Slot) -> u64 {
+        let counter = self.counter.state();
+        match slot {
+            Slot::Increments => counter.increments().get(&self.node_id),
+            Slot::Decrements => counter.decrements().get(&self.node_id),
+        }
+    }
+
+    fn add_own(&mut self, slot: Slot, n: u64) {
+        let delta = match slot {
+            Slot::Increments => n as i64,
+            Slot::Decrements => -(n as i64),
+        };
+        let node_id = &self.node_id;
+        self.counter.update(|counter| counter.add(node_id, delta));
+    }
+
+    // 只gossip写进seq-kv的值，别人手里自己的slot不会比持久化的大，重启后从持久化的值
+    // 接着加就行；重启后的本地add还在 pending 里
+    fn recover(&mut self, slot: Slot, value: u64) {
+        let persisted = self.persisted.get_mut(&slot).unwrap();
+        if persisted.recovered {
+            return;
+        }
+        persisted.recovered = true;
+        let own = self.own(slot);
+        self.add_own(slot, value.saturating_sub(own));
+    }
+
+    // seq-kv 确认了写入，写进去的 add 可以进counter gossip出去了
+    fn persisted(&mut self, slot: Slot) {
+        let Some((value, _, _)) = self.persisted.get_mut(&slot).unwrap().writing.take() else {
+            return;
+        };
+        let written = value.saturating_sub(self.own(slot));
+        self.persisted.get_mut(&slot).unwrap().pending -= written;
+        self.add_own(slot, written);
+    }
+
+    fn gossip(&mut self, output: &mut StdoutLock) -> Result<()> {
+        for message in self.counter.round(|state| Payload::Gossip { state }) {
+            message.send(&mut *output)?;
+        }
+        if !self.persist {
+            return Ok(());
+        }
+        // seq-kv 的回复在 step 里异步处理，不阻塞 add/read
+        for slot in SLOTS {
+            // 回复丢了就重写，迟到的回复在 inflight 里找不到，不会当成新写入的确认
+            let persisted = self.persisted.get_mut(&slot).unwrap();
+            if let Some((_, id, sent)) = persisted.writing {
+                if sent.elapsed() > self.write_timeout {
+                    persisted.writing = None;
+                    self.inflight.remove(&id);
+                }
+            }
+            let key = self.slot_key(slot);
+            let own = self.own(slot);
+            let persisted = self.persisted.get_mut(&slot).unwrap();
+            let payload = if !persisted.recovered {
+                Payload::KvRead { key }
+            } else if persisted.pending > 0 && persisted.writing.is_none() {
+                let value = own + persisted.pending;
+                persisted.writing = Some((value, self.id, Instant::now()));
+                Payload::Write {
+                    key,
+                    value: value as i64,
+                }
+            } else {
+                continue;
+            };
+            self.inflight.insert(self.id, slot);
+            let mut message =
+                Message::kv_message(&self.node_id, "seq-kv", Some(&mut self.id), None);
+            message.body.payload = payload;
+            message.send(&mut *output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counter that stays available while seq-kv isn't: an `add` goes into a local
+/// buffer that is saved to disk before it's acknowledged, and a timer flushes
+/// the whole buffer to the node's own `counter-<node>` key with a single CAS.
+/// Nobody else writes that key, so a flush whose outcome is unknown (lost
+/// reply, restart) stays unresolved until a read of the key shows its `to`, or
+/// a value other than its `from` that it can no longer land on. Until then the
+/// same CAS is re-sent and no new flush goes out.
+/// Reads sum the other nodes' keys as of the last flush tick, plus this node's
+/// flushed value and its unflushed buffer.
+struct BufferedCounterNode {
+    id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    own_key: String,
+    path: PathBuf,
+    buffer: Buffer,
+    // 别的节点的key最近一次读到的值
+    values: HashMap<String, i64>,
+    // 还没回复的seq-kv请求是读哪个key的，flush的CAS单独记
+    reads: HashMap<usize, String>,
+    flush_request: Option<(usize, Instant)>,
+    flush_timeout: Duration,
+}
+
+/// What survives a restart, in `COUNTER_BUFFER_DIR/counter-buffer-<node>.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Buffer {
+    /// Value of the own key after the last settled flush; unknown until read.
+    flushed: Option<i64>,
+    /// Adds acknowledged but not flushed yet, including a flush in flight.
+    pending: i64,
+    /// `(from, delta)` of a CAS whose outcome isn't known yet.
+    flushing: Option<(i64, i64)>,
+}
+
+impl Node<(), Payload, InjectedPayload> for BufferedCounterNode {
+    fn from_init(
+        _: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let interval = Duration::from_millis(env_or("COUNTER_FLUSH_MS", 200));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if tx.send(Event::Injected(InjectedPayload::Tick)).is_err() {
+                break;
+            }
+        });
+        // buffer 是这次运行的，不能落到共享的临时目录里被下一次运行读到
+        let dir: PathBuf = std::env::var("COUNTER_BUFFER_DIR")
+            .map_err(|_| GanError::Normal("buffered mode needs COUNTER_BUFFER_DIR".to_string()))?
+            .into();
+        let path = dir.join(format!("counter-buffer-{}.json", init.node_id));
+        let buffer = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Buffer::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            id: 1,
+            own_key: shard_key(&init.node_id),
+            node_id: init.node_id,
+            node_ids: init.node_ids,
+            path,
+            buffer,
+            values: HashMap::new(),
+            reads: HashMap::new(),
+            flush_request: None,
+            flush_timeout: Duration::from_millis(env_or("COUNTER_FLUSH_TIMEOUT_MS", 1000)),
+        })
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut StdoutLock,
+        _: &Receiver<Event<Payload, InjectedPayload>>,
+    ) -> Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Tick) => return self.flush(output),
+            Event::EOF => return Ok(()),
+        };
+        let flush_reply = input
+            .body
+            .in_reply_to
+            .is_some_and(|id| self.flush_request.is_some_and(|(req, _)| req == id));
+        if flush_reply {
+            self.flush_request = None;
+        }
+        let key = input.body.in_reply_to.and_then(|id| self.reads.remove(&id));
+        let mut reply = input.into_reply(Some(&mut self.id));
+        match reply.body.payload {
+            Payload::Add { delta } => {
+                self.buffer.pending += delta;
+                self.save()?;
+                reply.body.payload = Payload::AddOk;
+                reply.send(output)?;
+            }
+            Payload::Read => {
+                let others: i64 = self
+                    .values
+                    .iter()
+                    .filter(|(key, _)| **key != self.own_key)
+                    .map(|(_, value)| value)
+                    .sum();
+                let own = self.buffer.flushed.unwrap_or(0) + self.buffer.pending;
+                reply.body.payload = Payload::ReadOk {
+                    value: others + own,
+                };
+                reply.send(output)?;
+            }
+            Payload::CasOk if flush_reply => {
+                if let Some((from, delta)) = self.buffer.flushing.take() {
+                    self.settle(from + delta, delta)?;
+                }
+            }
+            Payload::ReadOk { value } => {
+                if let Some(key) = key {
+                    self.observe(key, value)?;
+                }
+            }
+            Payload::Error { code: 20, .. } if key.is_some() => {
+                self.observe(key.unwrap(), 0)?;
+            }
+            // CAS 失败或者出错了，结果等下一轮读自己的key来确定
+            Payload::Error { code, text } => {
+                eprintln!("counter flush error({code}): {text}");
+            }
+            Payload::WriteOk | Payload::CasOk => (),
+            Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::AddOk
+            | Payload::KvRead { .. }
+            | Payload::Gossip { .. } => {
+                return Err(GanError::Normal(
+                    "should not exist invalid response".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BufferedCounterNode {
+    fn save(&self) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_vec(&self.buffer)?)
+    }
+
+    /// A flush of `delta` landed and the own key is now `value`.
+    fn settle(&mut self, value: i64, delta: i64) -> Result<()> {
+        self.buffer.flushed = Some(value);
+        self.buffer.pending -= delta;
+        self.buffer.flushing = None;
+        self.save()
+    }
+
+    fn observe(&mut self, key: String, value: i64) -> Result<()> {
+        if key != self.own_key {
+            self.values.insert(key, value);
+            return Ok(());
+        }
+        match self.buffer.flushing {
+            // 只在不知道的时候用读到的值：读可能比刚确认的CAS先被seq-kv处理
+            None if self.buffer.flushed.is_none() => {
+                self.buffer.flushed = Some(value);
+                self.save()?;
+            }
+            None => (),
+            Some((from, delta)) if value == from + delta => self.settle(value, delta)?,
+            // 还是from：CAS可能还在路上，也可能丢了，超时后原样重发
+            Some((from, _)) if value == from => (),
+            Some(_) => {
+                // 只有本节点写这个key，既不是from也不是to，这个CAS不可能再成功了
+                self.buffer.flushed = Some(value);
+                self.buffer.flushing = None;
+                self.save()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, output: &mut StdoutLock) -> Result<()> {
+        if self
+            .flush_request
+            .is_some_and(|(_, sent)| sent.elapsed() > self.flush_timeout)
+        {
+            self.flush_request = None;
+        }
+        let cas = match (
+            self.flush_request,
+            self.buffer.flushed,
+            self.buffer.flushing,
+        ) {
+            (Some(_), _, _) => None,
+            // 上一个CAS还没结果，不能在它上面再叠一个
+            (None, _, Some(flushing)) => Some(flushing),
+            (None, Some(from), None) if self.buffer.pending != 0 => {
+                self.buffer.flushing = Some((from, self.buffer.pending));
+                self.save()?;
+                self.buffer.flushing
+            }
+            (None, _, None) => None,
+        };
+        if let Some((from, delta)) = cas {
+            self.flush_request = Some((self.id, Instant::now()));
+            let mut message =
+                Message::kv_message(&self.node_id, "seq-kv", Some(&mut self.id), None);
+            message.body.payload = Payload::Cas {
+                key: self.own_key.clone(),
+                from,
+                to: from + delta,
+                create_if_not_exists: from == 0,
+            };
+            message.send(&mut *output)?;
+        }
+        // 刷新别的节点的值，顺便确认自己key的值
+        self.reads.clear();
+        for node in &self.node_ids {
+            let key = shard_key(node);
+            self.reads.insert(self.id, key.clone());
+            let mut message =
+                Message::kv_message(&self.node_id, "seq-kv", Some(&mut self.id), None);
+            message.body.payload = Payload::KvRead { key };
+            message.send(&mut *output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Timer of the background work: gossip rounds, buffer flushes.
+enum InjectedPayload {
+    Tick,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
     Add {
-        delta: u64,
+        delta: i64,
     },
     AddOk,
     #[default]
     Read,
     ReadOk {
-        value: u64,
+        value: i64,
     },
     #[serde(rename = "read")]
     KvRead {
@@ -216,13 +782,13 @@ enum Payload {
     },
     Write {
         key: String,
-        value: u64,
+        value: i64,
     },
     WriteOk,
     Cas {
         key: String,
-        from: u64,
-        to: u64,
+        from: i64,
+        to: i64,
         create_if_not_exists: bool,
     },
     CasOk,
@@ -230,4 +796,7 @@ enum Payload {
         code: u8,
         text: String,
     },
+    Gossip {
+        state: PNCounter,
+    },
 }
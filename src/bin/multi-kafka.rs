@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::io::StdoutLock;
 use std::sync::mpsc::{Receiver, Sender};
@@ -17,77 +18,285 @@ struct KafkaNode {
     node_id: String,
     node_ids: Vec<String>,
     storage: LinKv,
+    /// Records of the keys this node owns. Only the owner writes a key, so
+    /// it hands out offsets from memory without a round trip to lin-kv.
+    logs: HashMap<String, KeyLog>,
     tx: Sender<Event<Payload>>,
 }
 
+/// Values of an owned key. Offsets start at `epoch << 32`, so a node that
+/// claims a key again after losing its records (a restart) stays above every
+/// offset handed out before.
+struct KeyLog {
+    base: u64,
+    values: Vec<u64>,
+}
+
+impl KeyLog {
+    fn new(epoch: u64) -> Self {
+        KeyLog {
+            base: epoch << 32,
+            values: Vec::new(),
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.base + self.values.len() as u64
+    }
+
+    fn push(&mut self, value: u64) -> u64 {
+        self.values.push(value);
+        self.end() - 1
+    }
+
+    fn from(&self, offset: u64) -> Vec<(u64, u64)> {
+        let skip = (offset.saturating_sub(self.base) as usize).min(self.values.len());
+        (self.base + skip as u64..)
+            .zip(self.values[skip..].iter().copied())
+            .collect()
+    }
+}
+
 impl KafkaNode {
-    fn send_proxy_node(
+    /// Index of the node owning `key`: numeric keys round robin, others by
+    /// hash.
+    fn owner(&self, key: &str) -> usize {
+        let k = key.parse::<u64>().unwrap_or_else(|_| fnv1a(key.as_bytes()));
+        (k % self.node_ids.len() as u64) as usize
+    }
+
+    /// Group `items` by the node owning their key.
+    fn by_owner<T>(
+        &self,
+        items: impl IntoIterator<Item = (String, T)>,
+    ) -> HashMap<usize, Vec<(String, T)>> {
+        let mut groups: HashMap<usize, Vec<_>> = HashMap::new();
+        for (key, item) in items {
+            groups
+                .entry(self.owner(&key))
+                .or_default()
+                .push((key, item));
+        }
+        groups
+    }
+
+    fn is_me(&self, idx: usize) -> bool {
+        self.node_ids[idx] == self.node_id
+    }
+
+    /// Append to a key this node owns, claiming it in lin-kv on its first
+    /// send.
+    fn append(
+        logs: &mut HashMap<String, KeyLog>,
+        storage: &mut LinKv,
+        rt: Runtime<Payload>,
+        key: String,
+        msg: u64,
+    ) -> Result<u64> {
+        let log = match logs.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let epoch = storage.claim(rt, entry.key())?;
+                entry.insert(KeyLog::new(epoch))
+            }
+        };
+        Ok(log.push(msg))
+    }
+
+    fn poll_local(
+        &self,
+        offsets: impl IntoIterator<Item = (String, u64)>,
+    ) -> HashMap<String, Vec<(u64, u64)>> {
+        offsets
+            .into_iter()
+            .filter_map(|(key, offset)| {
+                let msgs = self.logs.get(&key)?.from(offset);
+                (!msgs.is_empty()).then_some((key, msgs))
+            })
+            .collect()
+    }
+
+    fn ends_local(&self, keys: impl IntoIterator<Item = String>) -> HashMap<String, u64> {
+        keys.into_iter()
+            .map(|key| {
+                let end = self.logs.get(&key).map_or(0, KeyLog::end);
+                (key, end)
+            })
+            .collect()
+    }
+
+    /// Answer a request another node forwarded to the owner.
+    fn serve(
+        &mut self,
+        input: Message<Payload>,
+        output: &mut StdoutLock,
+        rx: &Receiver<Event<Payload>>,
+    ) -> Result<()> {
+        let mut reply = input.into_reply(Some(&mut self.id));
+        let rt = Runtime {
+            id: &mut self.id,
+            node_id: &self.node_id,
+            rx,
+            writer: output,
+            in_reply_to: None,
+        };
+        reply.body.payload = match reply.body.payload {
+            Payload::ForwardSend { key, msg } => {
+                match Self::append(&mut self.logs, &mut self.storage, rt, key, msg) {
+                    Ok(offset) => Payload::SendOk { offset },
+                    Err(e) => unavailable(e),
+                }
+            }
+            Payload::ForwardPoll { offsets } => Payload::PollOk {
+                msgs: self.poll_local(offsets),
+            },
+            Payload::LogEnd { keys } => Payload::LogEndOk {
+                ends: self.ends_local(keys),
+            },
+            other => return Err(unexpected(other)),
+        };
+        reply.send(output)
+    }
+
+    //   Forward
+    // A --------> B
+    // A waiting
+    //    Forward
+    // B ---------> A
+    // B waiting
+    // A, B在等待过程中需要处理别人转发过来的请求，然后返回结果，否则陷入死锁
+    /// Send `payload` to node `idx` and wait for its reply, serving the
+    /// requests other nodes forward here in the meantime.
+    fn request(
         &mut self,
-        payload: Payload,
         idx: usize,
+        payload: Payload,
         output: &mut StdoutLock,
         rx: &Receiver<Event<Payload>>,
     ) -> Result<Payload> {
-        let dest = self.node_ids.get(idx).unwrap();
-        let mut message =
-            Message::kv_message(self.node_id.as_str(), dest, Some(&mut self.id), None);
+        let dest = self.node_ids[idx].clone();
+        let mut message = Message::kv_message(&self.node_id, &dest, Some(&mut self.id), None);
         message.body.payload = payload;
         message.send(output)?;
+        let is_reply =
+            |m: &Message<Payload>| m.src == dest && m.body.in_reply_to == message.body.id;
+        let deadline = Instant::now() + TIMEOUT;
         loop {
-            let rt = Runtime {
-                id: &mut self.id,
-                node_id: &self.node_id,
-                rx,
-                writer: output,
-                in_reply_to: None,
-            };
-            let Event::Message(input) = rt.rx.recv()? else {
-                panic!("got injected event when there's no event injection");
-            };
-            let mut reply = input.reply(Some(rt.id));
-            let storage = &mut self.storage;
-            match input.body.payload {
-                Payload::SendOk { offset } => {
-                    return Ok(Payload::SendOk { offset });
-                }
-                //   Forward
-                // A --------> B
-                // A waiting
-                //    Forward
-                // B ---------> A
-                // B waiting
-                // A, B在等待过程中需要处理属于自己Forward Send 事件，然后返回结果，否则陷入死锁
-                Payload::ForwardSend { key, msg } => {
-                    let offset = storage.send(rt, key, msg)?;
-                    let payload = Payload::SendOk { offset };
-                    reply.body.payload = payload;
-                    reply.send(output)?;
-                    // 检查storage是否收到SendOk包，收到立刻发送这里loop接收，然后返回我们等到的offset。
-                    while let Some(index) = storage
-                        .stash_event
-                        .iter()
-                        .position(|c| matches!(&c.body.payload, &Payload::SendOk { .. }))
-                    {
-                        self.tx
-                            .send(Event::Message(storage.stash_event.remove(index)))?;
-                    }
-                }
-                Payload::Send { .. }
-                | Payload::Poll { .. }
-                | Payload::CommitOffsets { .. }
-                | Payload::ListCommittedOffsets { .. } => {
-                    storage.stash_event.push(input);
-                }
-                _ => {
-                    return Err(GanError::Normal(
-                        "should not exist invalid response".to_string(),
-                    ))
-                }
+            // serve 里等 lin-kv 的时候收到的消息都进了 stash，回复和转发请求也可能在里面
+            let stash = &mut self.storage.stash_event;
+            if let Some(i) = stash.iter().position(is_reply) {
+                return Ok(stash.remove(i).body.payload);
+            }
+            if let Some(i) = stash.iter().position(|m| m.body.payload.is_forwarded()) {
+                let input = stash.remove(i);
+                self.serve(input, output, rx)?;
+                continue;
             }
+            let input = self.storage.recv_until(rx, deadline)?;
+            if is_reply(&input) {
+                return Ok(input.body.payload);
+            }
+            if input.body.payload.is_forwarded() {
+                self.serve(input, output, rx)?;
+            } else if input.body.in_reply_to.is_some() {
+                // 没有别的请求在等回复，是之前超时的请求迟到的回复
+                late_reply(&input);
+            } else {
+                self.storage.stash_event.push(input);
+            }
+        }
+    }
+
+    /// Records of every key from its offset on, from the keys' owners.
+    fn poll(
+        &mut self,
+        offsets: HashMap<String, u64>,
+        output: &mut StdoutLock,
+        rx: &Receiver<Event<Payload>>,
+    ) -> Result<HashMap<String, Vec<(u64, u64)>>> {
+        let mut msgs = HashMap::new();
+        for (idx, offsets) in self.by_owner(offsets) {
+            if self.is_me(idx) {
+                msgs.extend(self.poll_local(offsets));
+                continue;
+            }
+            let offsets = offsets.into_iter().collect();
+            match self.request(idx, Payload::ForwardPoll { offsets }, output, rx)? {
+                Payload::PollOk { msgs: remote } => msgs.extend(remote),
+                other => return Err(unexpected(other)),
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// Offset after the last record of every key, from the keys' owners.
+    fn ends(
+        &mut self,
+        keys: impl IntoIterator<Item = String>,
+        output: &mut StdoutLock,
+        rx: &Receiver<Event<Payload>>,
+    ) -> Result<HashMap<String, u64>> {
+        let mut ends = HashMap::new();
+        for (idx, keys) in self.by_owner(keys.into_iter().map(|k| (k, ()))) {
+            let keys = keys.into_iter().map(|(k, ())| k);
+            if self.is_me(idx) {
+                ends.extend(self.ends_local(keys));
+                continue;
+            }
+            let keys = keys.collect();
+            match self.request(idx, Payload::LogEnd { keys }, output, rx)? {
+                Payload::LogEndOk { ends: remote } => ends.extend(remote),
+                other => return Err(unexpected(other)),
+            }
+        }
+        Ok(ends)
+    }
+
+    fn flush_stash(&mut self) -> Result<()> {
+        for event in self.storage.stash_event.drain(..) {
+            self.tx.send(Event::Message(event))?;
+        }
+        if std::mem::take(&mut self.storage.eof) {
+            self.tx.send(Event::EOF)?;
         }
+        Ok(())
     }
 }
 
+// FNV-1a，每个节点算出来都一样(DefaultHasher 不保证这一点)
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn late_reply(input: &Message<Payload>) {
+    eprintln!(
+        "kafka: dropping late reply from {}: {:?}",
+        input.src, input.body.payload
+    );
+}
+
+// temporarily-unavailable
+fn unavailable(e: GanError) -> Payload {
+    Payload::Error {
+        code: 11,
+        text: e.to_string(),
+    }
+}
+
+// timeout: 请求可能已经生效了，也可能没有
+fn indefinite(e: GanError) -> Payload {
+    Payload::Error {
+        code: 0,
+        text: e.to_string(),
+    }
+}
+
+fn unexpected(payload: Payload) -> GanError {
+    GanError::Normal(format!("should not exist invalid response {payload:?}"))
+}
+
 impl Node<(), Payload> for KafkaNode {
     fn from_init(_: (), init: Init, tx: Sender<Event<Payload>>) -> Result<Self>
     where
@@ -99,7 +308,9 @@ impl Node<(), Payload> for KafkaNode {
             node_ids: init.node_ids,
             storage: LinKv {
                 stash_event: Vec::new(),
+                eof: false,
             },
+            logs: HashMap::new(),
             tx,
         })
     }
@@ -110,191 +321,365 @@ impl Node<(), Payload> for KafkaNode {
         output: &mut StdoutLock,
         rx: &Receiver<Event<Payload>>,
     ) -> Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(()) => panic!("got injected event when there's no event injection"),
+            Event::EOF => return Ok(()),
         };
+        // receive a forward message
+        if input.body.payload.is_forwarded() {
+            self.serve(input, output, rx)?;
+            return self.flush_stash();
+        }
         let mut reply = input.into_reply(Some(&mut self.id));
-        let rt = Runtime {
-            id: &mut self.id,
-            node_id: &self.node_id,
-            rx,
-            writer: output,
-            in_reply_to: None,
-        };
         match reply.body.payload {
-            // receive a forward message
-            Payload::ForwardSend { key, msg } => {
-                let offset = self.storage.send(rt, key, msg)?;
-                reply.body.payload = Payload::SendOk { offset };
-            }
             Payload::Send { key, msg } => {
-                if let Some((k, nid)) = key
-                    .parse::<u64>()
-                    .ok()
-                    .zip((&self.node_id[1..]).parse::<u64>().ok())
-                {
-                    let idx = k % self.node_ids.len() as u64;
-                    if idx != nid {
-                        reply.body.payload = self.send_proxy_node(
-                            Payload::ForwardSend { key, msg },
-                            idx as usize,
-                            output,
-                            rx,
-                        )?;
-                        reply.send(output)?;
-                        for event in self.storage.stash_event.drain(..) {
-                            self.tx.send(Event::Message(event))?;
-                        }
-                        return Ok(());
+                let idx = self.owner(&key);
+                reply.body.payload = if self.is_me(idx) {
+                    let rt = Runtime {
+                        id: &mut self.id,
+                        node_id: &self.node_id,
+                        rx,
+                        writer: output,
+                        in_reply_to: None,
+                    };
+                    match Self::append(&mut self.logs, &mut self.storage, rt, key, msg) {
+                        Ok(offset) => Payload::SendOk { offset },
+                        Err(e) => unavailable(e),
                     }
-                }
-                let offset = self.storage.send(rt, key, msg)?;
-                reply.body.payload = Payload::SendOk { offset };
+                } else {
+                    // owner 可能已经追加了只是回复没到，不能说没发生；owner 自己
+                    // 认领失败回的 temporarily-unavailable 原样转给客户端
+                    self.request(idx, Payload::ForwardSend { key, msg }, output, rx)
+                        .unwrap_or_else(indefinite)
+                };
             }
             Payload::Poll { offsets } => {
-                let msgs = self.storage.poll(rt, offsets)?;
-                reply.body.payload = Payload::PollOk { msgs };
+                reply.body.payload = match self.poll(offsets, output, rx) {
+                    Ok(msgs) => Payload::PollOk { msgs },
+                    Err(e) => unavailable(e),
+                };
             }
-            Payload::CommitOffsets { offsets } => {
-                self.storage.commit_offsets(rt, offsets)?;
-                reply.body.payload = Payload::CommitOffsetsOk;
+            Payload::CommitOffsets { offsets, group } => {
+                let committed = self
+                    .ends(offsets.keys().cloned(), output, rx)
+                    .and_then(|ends| {
+                        let rt = Runtime {
+                            id: &mut self.id,
+                            node_id: &self.node_id,
+                            rx,
+                            writer: output,
+                            in_reply_to: None,
+                        };
+                        self.storage
+                            .commit_offsets(rt, group.as_deref(), offsets, &ends)
+                    });
+                reply.body.payload = match committed {
+                    Ok(()) => Payload::CommitOffsetsOk,
+                    Err(GanError::Rpc { code, text }) => Payload::Error { code, text },
+                    Err(e) => unavailable(e),
+                };
             }
-            Payload::ListCommittedOffsets { keys } => {
-                let committed_offsets = self.storage.list_committed_offsets(rt, keys);
+            Payload::ListCommittedOffsets { keys, group } => {
+                let rt = Runtime {
+                    id: &mut self.id,
+                    node_id: &self.node_id,
+                    rx,
+                    writer: output,
+                    in_reply_to: None,
+                };
+                let committed_offsets =
+                    self.storage
+                        .list_committed_offsets(rt, group.as_deref(), keys);
                 reply.body.payload = Payload::ListCommittedOffsetsOk {
                     offsets: committed_offsets,
                 };
             }
+            Payload::JoinGroup {
+                ref group,
+                ref member,
+                ref keys,
+            } => {
+                let rt = Runtime {
+                    id: &mut self.id,
+                    node_id: &self.node_id,
+                    rx,
+                    writer: output,
+                    in_reply_to: None,
+                };
+                let joined = self.storage.update_group(rt, group, |g| {
+                    g.join(member, keys.iter().cloned(), unix_millis());
+                    let keys = g.assignment(member).unwrap_or_default().to_vec();
+                    (g.generation(), keys)
+                });
+                reply.body.payload = match joined {
+                    Ok((generation, keys)) => Payload::JoinGroupOk { generation, keys },
+                    Err(e) => unavailable(e),
+                };
+            }
+            Payload::Heartbeat {
+                ref group,
+                ref member,
+            } => {
+                let rt = Runtime {
+                    id: &mut self.id,
+                    node_id: &self.node_id,
+                    rx,
+                    writer: output,
+                    in_reply_to: None,
+                };
+                let beat = self.storage.update_group(rt, group, |g| {
+                    let keys = g.assignment(member)?.to_vec();
+                    g.heartbeat(member, unix_millis());
+                    Some((g.generation(), keys))
+                });
+                reply.body.payload = match beat {
+                    Ok(Some((generation, keys))) => Payload::HeartbeatOk { generation, keys },
+                    // precondition-failed: 不在组里了，要重新join
+                    Ok(None) => Payload::Error {
+                        code: 22,
+                        text: format!("{member} is not a member, join again"),
+                    },
+                    Err(e) => unavailable(e),
+                };
+            }
+            Payload::LeaveGroup {
+                ref group,
+                ref member,
+            } => {
+                let rt = Runtime {
+                    id: &mut self.id,
+                    node_id: &self.node_id,
+                    rx,
+                    writer: output,
+                    in_reply_to: None,
+                };
+                let left = self.storage.update_group(rt, group, |g| g.leave(member));
+                reply.body.payload = match left {
+                    Ok(()) => Payload::LeaveGroupOk,
+                    Err(e) => unavailable(e),
+                };
+            }
             Payload::Error { code, text } => {
                 eprintln!("kafka node step call error({code}): {text}");
                 return Ok(());
             }
+            // 等待超时之后才到的回复，请求已经按 temporarily-unavailable 回复过了
             Payload::SendOk { .. }
+            | Payload::PollOk { .. }
+            | Payload::LogEndOk { .. }
+            | Payload::ReadOk { .. }
+            | Payload::CasOk
+            | Payload::WriteOk => {
+                late_reply(&reply.into_reply(None));
+                return self.flush_stash();
+            }
+            Payload::ForwardSend { .. }
+            | Payload::ForwardPoll { .. }
+            | Payload::LogEnd { .. }
             | Payload::Cas { .. }
             | Payload::KvRead { .. }
-            | Payload::CasOk
-            | Payload::ReadOk { .. }
             | Payload::Write { .. }
-            | Payload::WriteOk
             | Payload::CommitOffsetsOk
             | Payload::ListCommittedOffsetsOk { .. }
-            | Payload::PollOk { .. } => {
+            | Payload::JoinGroupOk { .. }
+            | Payload::HeartbeatOk { .. }
+            | Payload::LeaveGroupOk => {
                 return Err(GanError::Normal(
                     "should not exist invalid response for step".to_string(),
                 ));
             }
         }
         reply.send(output)?;
-        for event in self.storage.stash_event.drain(..) {
-            self.tx.send(Event::Message(event))?;
-        }
-        Ok(())
+        self.flush_stash()
     }
 }
 
 struct LinKv {
     stash_event: Vec<Message<Payload>>,
+    // 等回复的时候读到了输入结束，flush_stash 时再交给 main_loop
+    eof: bool,
 }
 
 const KV_NAME: &str = "lin-kv";
 const PREFIX_COMMIT: &str = "commit";
-const PREFIX_LATEST: &str = "latest";
-const PREFIX_ENTRY: &str = "entry";
-const BATCH_SIZE: u64 = 20;
+const PREFIX_GROUP: &str = "group";
+const PREFIX_OWNER: &str = "owner";
+const TIMEOUT: Duration = Duration::from_secs(1);
 
-trait EntriesExt {
-    fn append(&mut self, offset: u64, value: u64);
-    fn new_key(key: &str, offset: u64) -> Self;
-}
-
-impl EntriesExt for String {
-    fn append(&mut self, offset: u64, value: u64) {
-        if !self.is_empty() {
-            self.push(',');
-        }
-        self.push_str(format!("{offset}:{value}").as_str());
-    }
-
-    fn new_key(key: &str, offset: u64) -> Self {
-        let start = offset - offset % BATCH_SIZE;
-        format!("{}_{}_{}-{}", PREFIX_ENTRY, key, start, start + BATCH_SIZE)
-    }
+/// Owner of a key, kept in lin-kv under `owner_<key>`. The epoch goes up
+/// every time the key is claimed.
+#[derive(Debug, Serialize, Deserialize)]
+struct Ownership {
+    node: String,
+    epoch: u64,
 }
 
 impl LinKv {
-    fn send(&mut self, mut rt: Runtime<Payload>, key: String, value: u64) -> Result<u64> {
-        let latest_key = format!("{}_{}", PREFIX_LATEST, key);
-        let offset = self
-            .read(&mut rt, &latest_key)?
-            .parse::<u64>()
-            .map(|x| x + 1)
-            .unwrap_or(0);
-        self.write(&mut rt, latest_key, offset.to_string())?;
-        // write batch entry
-        let entry_key = String::new_key(key.as_str(), offset);
-        let mut entries = self.read(&mut rt, &entry_key)?;
-        entries.append(offset, value);
-        self.write(&mut rt, entry_key, entries)?;
-        Ok(offset)
+    /// Claim `key` for this node and return the epoch to number its offsets
+    /// with. A key claimed before, by an earlier run of this node or by
+    /// another node before the cluster changed, moves to the next epoch.
+    fn claim(&mut self, mut rt: Runtime<Payload>, key: &str) -> Result<u64> {
+        let owner_key = format!("{}_{}", PREFIX_OWNER, key);
+        Retry::from_env().run(
+            |_| {
+                let current = self.read(&mut rt, &owner_key)?;
+                let epoch = if current.is_empty() {
+                    0
+                } else {
+                    let previous: Ownership = serde_json::from_str(&current)?;
+                    eprintln!("kafka: taking over {key} from {previous:?}");
+                    previous.epoch + 1
+                };
+                let claimed = serde_json::to_string(&Ownership {
+                    node: rt.node_id.to_string(),
+                    epoch,
+                })?;
+                self.compare_exchange(&mut rt, &owner_key, current, claimed, true)?;
+                Ok(epoch)
+            },
+            |e| matches!(e, GanError::PreconditionFailed),
+        )
     }
 
-    fn read_segment(
-        &mut self,
-        rt: &mut Runtime<Payload>,
-        ofs: u64,
-        key: &str,
-        key_offsets: &mut Vec<(u64, u64)>,
-    ) -> Result<()> {
-        let mut start = ofs - ofs % BATCH_SIZE;
+    /// Send `payload` to lin-kv and wait for the reply to it; everything else
+    /// that comes in meanwhile is stashed for later.
+    fn call(&mut self, rt: &mut Runtime<'_, '_, Payload>, payload: Payload) -> Result<Payload> {
+        let mut message = Message::kv_message(rt.node_id, KV_NAME, Some(rt.id), rt.in_reply_to);
+        message.body.payload = payload;
+        message.send(rt.writer)?;
+        let deadline = Instant::now() + TIMEOUT;
         loop {
-            let entry_key = String::new_key(&key, start);
-            let entries = self.read(rt, &entry_key)?;
-            if entries.is_empty() {
-                break;
+            let input = self.recv_until(rt.rx, deadline)?;
+            if input.src != KV_NAME {
+                // 可能是外面 request 在等的回复，留给它
+                self.stash_event.push(input);
+            } else if input.body.in_reply_to == message.body.id {
+                rt.in_reply_to = input.body.id;
+                return Ok(input.body.payload);
+            } else {
+                late_reply(&input);
             }
-            for entry in entries.split(',') {
-                let Some((o, v)) = entry.split_once(':').and_then(|(o, v)| o.parse().ok().zip(v.parse().ok())) else {
-                    continue;
-                };
-                if o >= ofs {
-                    key_offsets.push((o, v));
-                }
-            }
-            start += BATCH_SIZE;
         }
-        Ok(())
     }
 
-    fn poll(
+    /// Next message, or an error once `deadline` has passed or the input
+    /// ended.
+    fn recv_until(
         &mut self,
-        mut rt: Runtime<Payload>,
-        offsets: HashMap<String, u64>,
-    ) -> Result<HashMap<String, Vec<(u64, u64)>>> {
-        let mut result = HashMap::new();
-        if offsets.is_empty() {
-            return Ok(result);
+        rx: &Receiver<Event<Payload>>,
+        deadline: Instant,
+    ) -> Result<Message<Payload>> {
+        if self.eof {
+            return Err(GanError::Normal("input closed".to_string()));
         }
-        for (key, ofs) in offsets.into_iter() {
-            let mut key_offsets = Vec::new();
-            self.read_segment(&mut rt, ofs, &key, &mut key_offsets)?;
-            if !key_offsets.is_empty() {
-                result.insert(key, key_offsets);
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(Event::Message(input)) => Ok(input),
+            Ok(Event::EOF) => {
+                self.eof = true;
+                Err(GanError::Normal("input closed".to_string()))
+            }
+            Ok(Event::Injected(())) => {
+                panic!("got injected event when there's no event injection")
             }
+            Err(_) => Err(GanError::Normal("wait response timeout".to_string())),
+        }
+    }
+
+    /// lin-kv key of the offset `group` committed for `key`. Commits without
+    /// a group keep the key they had before groups existed.
+    fn commit_key(group: Option<&str>, key: &str) -> String {
+        match group.filter(|g| !g.is_empty()) {
+            Some(group) => format!("{}_{}/{}", PREFIX_COMMIT, group, key),
+            None => format!("{}_{}", PREFIX_COMMIT, key),
         }
-        Ok(result)
     }
 
+    /// Move the committed offsets of `group` forward, all or none like
+    /// single-kafka. Every offset is checked against the end of its key in
+    /// `ends` (key-does-not-exist if past it) and against the committed one
+    /// (precondition-failed if behind) before anything is written; then each
+    /// key is raised with a CAS. lin-kv has no multi-key CAS, so only a client
+    /// committing the same keys concurrently can still make a later key fail
+    /// after earlier ones were written. Losing lin-kv once a CAS has been sent
+    /// is reported as a timeout, since the commit may have landed.
     fn commit_offsets(
         &mut self,
         mut rt: Runtime<Payload>,
+        group: Option<&str>,
         offsets: HashMap<String, u64>,
+        ends: &HashMap<String, u64>,
     ) -> Result<()> {
         if offsets.is_empty() {
             return Ok(());
         }
+        for (key, &ofs) in &offsets {
+            let end = ends.get(key).copied().unwrap_or(0);
+            if ofs > end {
+                return Err(GanError::Rpc {
+                    code: 20,
+                    text: format!("offset {ofs} is past the end of {key} at {end}"),
+                });
+            }
+        }
+        for (key, &ofs) in &offsets {
+            let current = self.read(&mut rt, &Self::commit_key(group, key))?;
+            if let Ok(current) = current.parse::<u64>() {
+                if ofs < current {
+                    return Err(GanError::Rpc {
+                        code: 22,
+                        text: format!("offset {ofs} of {key} is behind the committed {current}"),
+                    });
+                }
+            }
+        }
+        let retry = Retry::from_env();
+        let mut cas_sent = false;
         for (key, ofs) in offsets.into_iter() {
-            let commit_key = format!("{}_{}", PREFIX_COMMIT, key);
-            self.write(&mut rt, commit_key, ofs.to_string())?;
+            let commit_key = Self::commit_key(group, &key);
+            let committed = retry.run(
+                |_| {
+                    let current = self.read(&mut rt, &commit_key)?;
+                    match current.parse::<u64>() {
+                        Ok(current) if ofs < current => Err(GanError::Rpc {
+                            code: 22,
+                            text: format!(
+                                "offset {ofs} of {key} is behind the committed {current}"
+                            ),
+                        }),
+                        Ok(current) if ofs == current => Ok(()),
+                        _ => {
+                            cas_sent = true;
+                            self.compare_exchange(
+                                &mut rt,
+                                &commit_key,
+                                current,
+                                ofs.to_string(),
+                                true,
+                            )
+                        }
+                    }
+                },
+                |e| matches!(e, GanError::PreconditionFailed),
+            );
+            match committed {
+                Ok(()) => (),
+                // lin-kv 明确拒绝了，没写进去
+                Err(
+                    e @ (GanError::Rpc { .. }
+                    | GanError::PreconditionFailed
+                    | GanError::RetriesExhausted { .. }),
+                ) => return Err(e),
+                // 超时之类：发出去的CAS可能已经生效了
+                Err(e) if cas_sent => {
+                    return Err(GanError::Rpc {
+                        code: 0,
+                        text: e.to_string(),
+                    })
+                }
+                Err(e) => return Err(e),
+            }
         }
         Ok(())
     }
@@ -302,6 +687,7 @@ impl LinKv {
     fn list_committed_offsets(
         &mut self,
         mut rt: Runtime<Payload>,
+        group: Option<&str>,
         keys: Vec<String>,
     ) -> HashMap<String, u64> {
         if keys.is_empty() {
@@ -310,13 +696,44 @@ impl LinKv {
         keys.into_iter()
             .filter_map(|k| {
                 let offset = self
-                    .read(&mut rt, format!("{}_{}", PREFIX_COMMIT, k).as_str())
+                    .read(&mut rt, &Self::commit_key(group, &k))
                     .ok()
                     .and_then(|c| c.parse().ok());
                 Some(k).zip(offset)
             })
             .collect()
     }
+
+    /// Apply `f` to the group `name`, kept as JSON in lin-kv and shared by all
+    /// nodes: read it, drop expired members, and CAS the result back, again
+    /// from the top if another node changed the group in between.
+    fn update_group<T>(
+        &mut self,
+        mut rt: Runtime<Payload>,
+        name: &str,
+        mut f: impl FnMut(&mut ConsumerGroup) -> T,
+    ) -> Result<T> {
+        let key = format!("{}_{}", PREFIX_GROUP, name);
+        let session = group::session_timeout();
+        Retry::from_env().run(
+            |_| {
+                let current = self.read(&mut rt, &key)?;
+                let mut group = if current.is_empty() {
+                    ConsumerGroup::default()
+                } else {
+                    serde_json::from_str(&current)?
+                };
+                group.expire(unix_millis(), session);
+                let result = f(&mut group);
+                let updated = serde_json::to_string(&group)?;
+                if updated != current {
+                    self.compare_exchange(&mut rt, &key, current, updated, true)?;
+                }
+                Ok(result)
+            },
+            |e| matches!(e, GanError::PreconditionFailed),
+        )
+    }
 }
 
 impl KV for LinKv {
@@ -326,46 +743,11 @@ impl KV for LinKv {
         let payload = Payload::KvRead {
             key: key.to_string(),
         };
-        let mut message = Message::kv_message(rt.node_id, KV_NAME, Some(rt.id), rt.in_reply_to);
-        message.body.payload = payload;
-        message.send(rt.writer)?;
-        let timeout = Duration::from_secs(1);
-        let now = Instant::now();
-        loop {
-            let Event::Message(input) = rt.rx.recv()? else {
-                panic!("got injected event when there's no event injection");
-            };
-            match input.body.payload {
-                Payload::ReadOk { value } => {
-                    rt.in_reply_to = input.body.id;
-                    return Ok(value);
-                }
-                Payload::Error { code, .. } if code == 20 => {
-                    rt.in_reply_to = input.body.id;
-                    return Ok(Default::default());
-                }
-                Payload::Error { code, text } => {
-                    rt.in_reply_to = input.body.id;
-                    return Err(GanError::Rpc { code, text });
-                }
-
-                Payload::Send { .. }
-                | Payload::Poll { .. }
-                | Payload::SendOk { .. }
-                | Payload::CommitOffsets { .. }
-                | Payload::ListCommittedOffsets { .. }
-                | Payload::ForwardSend { .. } => {
-                    self.stash_event.push(input);
-                }
-                _ => {
-                    return Err(GanError::Normal(
-                        "should not exist invalid response".to_string(),
-                    ))
-                }
-            }
-            if now.elapsed() >= timeout {
-                return Err(GanError::Normal("wait response timeout".to_string()));
-            }
+        match self.call(rt, payload)? {
+            Payload::ReadOk { value } => Ok(value),
+            Payload::Error { code: 20, .. } => Ok(Default::default()),
+            Payload::Error { code, text } => Err(GanError::Rpc { code, text }),
+            other => Err(unexpected(other)),
         }
     }
 
@@ -375,42 +757,10 @@ impl KV for LinKv {
         key: String,
         value: Self::Value,
     ) -> Result<()> {
-        let payload = Payload::Write { key, value };
-        let mut message = Message::kv_message(rt.node_id, KV_NAME, Some(rt.id), rt.in_reply_to);
-        message.body.payload = payload;
-        message.send(rt.writer)?;
-        let timeout = Duration::from_secs(1);
-        let now = Instant::now();
-        loop {
-            let Event::Message(input) = rt.rx.recv()? else {
-                panic!("got injected event when there's no event injection");
-            };
-            match input.body.payload {
-                Payload::WriteOk => {
-                    rt.in_reply_to = input.body.id;
-                    return Ok(());
-                }
-                Payload::Error { code, text } => {
-                    rt.in_reply_to = input.body.id;
-                    return Err(GanError::Rpc { code, text });
-                }
-                Payload::Send { .. }
-                | Payload::Poll { .. }
-                | Payload::SendOk { .. }
-                | Payload::CommitOffsets { .. }
-                | Payload::ListCommittedOffsets { .. }
-                | Payload::ForwardSend { .. } => {
-                    self.stash_event.push(input);
-                }
-                _ => {
-                    return Err(GanError::Normal(
-                        "should not exist invalid response".to_string(),
-                    ))
-                }
-            }
-            if now.elapsed() >= timeout {
-                return Err(GanError::Normal("wait response timeout".to_string()));
-            }
+        match self.call(rt, Payload::Write { key, value })? {
+            Payload::WriteOk => Ok(()),
+            Payload::Error { code, text } => Err(GanError::Rpc { code, text }),
+            other => Err(unexpected(other)),
         }
     }
 
@@ -428,47 +778,13 @@ impl KV for LinKv {
             to,
             create_if_not_exists,
         };
-        let mut message = Message::kv_message(rt.node_id, KV_NAME, Some(rt.id), rt.in_reply_to);
-        message.body.payload = payload;
-        message.send(rt.writer)?;
-        let timeout = Duration::from_secs(1);
-        let now = Instant::now();
-        loop {
-            let Event::Message(input) = rt.rx.recv()? else {
-                panic!("got injected event when there's no event injection");
-            };
-            match input.body.payload {
-                Payload::CasOk => {
-                    rt.in_reply_to = input.body.id;
-                    return Ok(());
-                }
-                Payload::Error { code, text } => {
-                    rt.in_reply_to = input.body.id;
-                    // The requested operation expected some conditions to hold, and those conditions were not met.
-                    if code == 22 {
-                        return Err(GanError::PreconditionFailed);
-                    } else if code == 20 {
-                        return Err(GanError::KeyNotExist);
-                    }
-                    return Err(GanError::Rpc { code, text });
-                }
-                Payload::Send { .. }
-                | Payload::SendOk { .. }
-                | Payload::Poll { .. }
-                | Payload::CommitOffsets { .. }
-                | Payload::ListCommittedOffsets { .. }
-                | Payload::ForwardSend { .. } => {
-                    self.stash_event.push(input);
-                }
-                _ => {
-                    return Err(GanError::Normal(
-                        "should not exist invalid response".to_string(),
-                    ))
-                }
-            }
-            if now.elapsed() >= timeout {
-                return Err(GanError::Normal("wait response timeout".to_string()));
-            }
+        match self.call(rt, payload)? {
+            Payload::CasOk => Ok(()),
+            // The requested operation expected some conditions to hold, and those conditions were not met.
+            Payload::Error { code: 22, .. } => Err(GanError::PreconditionFailed),
+            Payload::Error { code: 20, .. } => Err(GanError::KeyNotExist),
+            Payload::Error { code, text } => Err(GanError::Rpc { code, text }),
+            other => Err(unexpected(other)),
         }
     }
 }
@@ -490,20 +806,57 @@ enum Payload {
     Poll {
         offsets: HashMap<String, u64>,
     },
+    /// Poll of keys the receiver owns.
+    ForwardPoll {
+        offsets: HashMap<String, u64>,
+    },
+    /// Ends of keys the receiver owns, to check commits against.
+    LogEnd {
+        keys: Vec<String>,
+    },
+    LogEndOk {
+        ends: HashMap<String, u64>,
+    },
     PollOk {
         msgs: HashMap<String, Vec<(u64, u64)>>,
     },
     CommitOffsets {
         offsets: HashMap<String, u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     #[default]
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, u64>,
     },
+    JoinGroup {
+        group: String,
+        member: String,
+        keys: Vec<String>,
+    },
+    JoinGroupOk {
+        generation: u64,
+        keys: Vec<String>,
+    },
+    Heartbeat {
+        group: String,
+        member: String,
+    },
+    HeartbeatOk {
+        generation: u64,
+        keys: Vec<String>,
+    },
+    LeaveGroup {
+        group: String,
+        member: String,
+    },
+    LeaveGroupOk,
     Error {
         code: u8,
         text: String,
@@ -529,6 +882,16 @@ enum Payload {
     CasOk,
 }
 
+impl Payload {
+    /// Requests only the owner of their keys answers.
+    fn is_forwarded(&self) -> bool {
+        matches!(
+            self,
+            Payload::ForwardSend { .. } | Payload::ForwardPoll { .. } | Payload::LogEnd { .. }
+        )
+    }
+}
+
 // Both care about giving an illusion of a single copy.
 //     – From the outside observer, the system should (almost)
 // behave as if there’s only a single copy.
@@ -1,14 +1,28 @@
-use std::collections::{HashMap, VecDeque};
-use std::io::StdoutLock;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{StdoutLock, Write};
 use std::marker::PhantomData;
-use std::sync::mpsc::Receiver;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use rustengan::*;
 
 fn main() -> Result<()> {
-    main_loop::<_, KafkaNode<String, u64>, _, _>(())?;
+    // 消息的类型: json (任意JSON), string, bytes (数字数组) 或 u64
+    match std::env::var("KAFKA_VALUE_TYPE").as_deref() {
+        Ok("string") => main_loop::<_, KafkaNode<String, String>, _, InjectedPayload>(())?,
+        Ok("bytes") => main_loop::<_, KafkaNode<String, Vec<u8>>, _, InjectedPayload>(())?,
+        Ok("u64") => main_loop::<_, KafkaNode<String, u64>, _, InjectedPayload>(())?,
+        _ => main_loop::<_, KafkaNode<String, serde_json::Value>, _, InjectedPayload>(())?,
+    }
     Ok(())
 }
 
@@ -17,55 +31,180 @@ struct KafkaNode<K, V> {
     #[allow(unused)]
     node_id: String,
     storage: KafkaStorage<K, V>,
+    poll_stats: Option<PollStats>,
+    retention: RetentionConfig,
+    injecter: Sender<Event<Payload, InjectedPayload>>,
+    // 同一时间只跑一个压缩任务
+    compacting: bool,
+    // 组成员只在内存里，节点重启后消费者重新join
+    groups: HashMap<String, ConsumerGroup>,
+    session_timeout: Duration,
+}
+
+enum InjectedPayload {
+    /// Apply retention and maybe start compacting a segment.
+    Maintain,
+    Compacted(Result<Compacted>),
 }
 
-impl Node<(), Payload> for KafkaNode<String, u64> {
-    fn from_init(_: (), init: Init, _: std::sync::mpsc::Sender<Event<Payload>>) -> Result<Self>
+/// Poll latency, logged every 1000 polls with `KAFKA_POLL_STATS=1`.
+#[derive(Default)]
+struct PollStats {
+    polls: u32,
+    total: Duration,
+}
+
+impl<V> Node<(), Payload, InjectedPayload> for KafkaNode<String, V>
+where
+    V: IntoBytes + FromBytes + Serialize + DeserializeOwned,
+{
+    fn from_init(_: (), init: Init, tx: Sender<Event<Payload, InjectedPayload>>) -> Result<Self>
     where
         Self: Sized,
     {
+        let retention = RetentionConfig::from_env();
+        if retention.enabled() {
+            let tx = tx.clone();
+            let interval = retention.interval;
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                if tx.send(Event::Injected(InjectedPayload::Maintain)).is_err() {
+                    break;
+                }
+            });
+        }
         Ok(KafkaNode {
             id: 1,
             node_id: init.node_id,
-            storage: KafkaStorage {
-                data_block: Default::default(),
-                topic_offsets: Default::default(),
-                topic_committed_offsets: Default::default(),
-                current_offset: 0,
-                _mark: PhantomData,
-            },
+            storage: KafkaStorage::open(LogConfig::from_env())?,
+            poll_stats: (env_or("KAFKA_POLL_STATS", 0u8) != 0).then(PollStats::default),
+            retention,
+            injecter: tx,
+            compacting: false,
+            groups: HashMap::new(),
+            session_timeout: group::session_timeout(),
         })
     }
 
     fn step(
         &mut self,
-        input: Event<Payload>,
+        input: Event<Payload, InjectedPayload>,
         output: &mut StdoutLock,
-        _: &Receiver<Event<Payload>>,
+        _: &Receiver<Event<Payload, InjectedPayload>>,
     ) -> Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Maintain) => return self.maintain(),
+            Event::Injected(InjectedPayload::Compacted(result)) => {
+                self.compacting = false;
+                match result {
+                    Ok(compacted) => self.storage.finish_compaction(compacted)?,
+                    Err(e) => eprintln!("kafka compaction failed: {e}"),
+                }
+                return Ok(());
+            }
+            Event::EOF => return Ok(()),
         };
         let mut reply = input.into_reply(Some(&mut self.id));
         match reply.body.payload {
             Payload::Send { key, msg } => {
+                let msg = match serde_json::from_value(msg) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        // malformed-request
+                        reply.body.payload = Payload::Error {
+                            code: 12,
+                            text: format!("malformed msg: {e}"),
+                        };
+                        return reply.send(output);
+                    }
+                };
                 let offset = self.storage.send(key, msg)?;
                 reply.body.payload = Payload::SendOk { offset };
             }
-            Payload::Poll { offsets } => {
-                let msgs = self.storage.poll(offsets)?;
-                reply.body.payload = Payload::PollOk { msgs };
+            Payload::Poll { offsets, limits } => {
+                let start = Instant::now();
+                let (msgs, more) = self.storage.poll(offsets, &limits)?;
+                if let Some(stats) = &mut self.poll_stats {
+                    stats.polls += 1;
+                    stats.total += start.elapsed();
+                    if stats.polls == 1000 {
+                        eprintln!(
+                            "kafka poll: {:?} on average over {} polls, log has {} records",
+                            stats.total / stats.polls,
+                            stats.polls,
+                            self.storage.log.next_offset()
+                        );
+                        *stats = PollStats::default();
+                    }
+                }
+                let msgs = msgs
+                    .into_iter()
+                    .map(|(key, values)| {
+                        let values = values
+                            .into_iter()
+                            .map(|(offset, v)| Ok((offset, serde_json::to_value(v)?)))
+                            .collect::<Result<_>>()?;
+                        Ok((key, values))
+                    })
+                    .collect::<Result<_>>()?;
+                reply.body.payload = Payload::PollOk { msgs, more };
             }
-            Payload::CommitOffsets { offsets } => {
-                self.storage.commit_offsets(offsets)?;
-                reply.body.payload = Payload::CommitOffsetsOk;
+            Payload::CommitOffsets { offsets, group } => {
+                let committed = self
+                    .storage
+                    .commit_offsets(group.as_deref().unwrap_or_default(), offsets);
+                reply.body.payload = match committed {
+                    Ok(()) => Payload::CommitOffsetsOk,
+                    Err(GanError::Rpc { code, text }) => Payload::Error { code, text },
+                    Err(e) => return Err(e),
+                };
             }
-            Payload::ListCommittedOffsets { keys } => {
-                let committed_offsets = self.storage.list_committed_offsets(keys);
+            Payload::ListCommittedOffsets { keys, group } => {
+                let committed_offsets = self
+                    .storage
+                    .list_committed_offsets(group.as_deref().unwrap_or_default(), keys);
                 reply.body.payload = Payload::ListCommittedOffsetsOk {
                     offsets: committed_offsets,
                 };
             }
+            Payload::JoinGroup {
+                ref group,
+                ref member,
+                ref mut keys,
+            } => {
+                let group = self.group(group);
+                group.join(member, std::mem::take(keys), unix_millis());
+                reply.body.payload = Payload::JoinGroupOk {
+                    generation: group.generation(),
+                    keys: group.assignment(member).unwrap_or_default().to_vec(),
+                };
+            }
+            Payload::Heartbeat {
+                ref group,
+                ref member,
+            } => {
+                let group = self.group(group);
+                reply.body.payload = if group.heartbeat(member, unix_millis()) {
+                    Payload::HeartbeatOk {
+                        generation: group.generation(),
+                        keys: group.assignment(member).unwrap_or_default().to_vec(),
+                    }
+                } else {
+                    // precondition-failed: 不在组里了，要重新join
+                    Payload::Error {
+                        code: 22,
+                        text: format!("{member} is not a member, join again"),
+                    }
+                };
+            }
+            Payload::LeaveGroup {
+                ref group,
+                ref member,
+            } => {
+                self.group(group).leave(member);
+                reply.body.payload = Payload::LeaveGroupOk;
+            }
             Payload::Error { code, text } => {
                 eprintln!("kafka node step call error({code}): {text}");
                 return Ok(());
@@ -73,6 +212,9 @@ impl Node<(), Payload> for KafkaNode<String, u64> {
             Payload::SendOk { .. }
             | Payload::CommitOffsetsOk
             | Payload::ListCommittedOffsetsOk { .. }
+            | Payload::JoinGroupOk { .. }
+            | Payload::HeartbeatOk { .. }
+            | Payload::LeaveGroupOk
             | Payload::PollOk { .. } => {
                 return Err(GanError::Normal(
                     "should not exist invalid response".to_string(),
@@ -84,183 +226,1209 @@ impl Node<(), Payload> for KafkaNode<String, u64> {
     }
 }
 
+impl<K, V> KafkaNode<K, V> {
+    /// `name` with the members that missed their heartbeats dropped.
+    fn group(&mut self, name: &str) -> &mut ConsumerGroup {
+        let group = self.groups.entry(name.to_string()).or_default();
+        group.expire(unix_millis(), self.session_timeout);
+        group
+    }
+}
+
+impl<K, V> KafkaNode<K, V>
+where
+    K: Clone + IntoBytes + FromBytes + Eq + std::hash::Hash + Serialize + DeserializeOwned,
+    V: IntoBytes + FromBytes,
+{
+    /// Drop the segments retention lets go of, then hand the next sealed
+    /// segment to a compaction thread. The thread only reads the sealed
+    /// segment and writes a new file, so sends and polls carry on meanwhile;
+    /// the result is swapped in when it comes back as an injected event.
+    fn maintain(&mut self) -> Result<()> {
+        self.storage.enforce_retention(&self.retention)?;
+        if !self.retention.compact || self.compacting {
+            return Ok(());
+        }
+        let Some(job) = self.storage.compaction_job() else {
+            return Ok(());
+        };
+        self.compacting = true;
+        let tx = self.injecter.clone();
+        std::thread::spawn(move || {
+            let result = job.run();
+            let _ = tx.send(Event::Injected(InjectedPayload::Compacted(result)));
+        });
+        Ok(())
+    }
+}
+
 struct KafkaStorage<K, V> {
-    // storage, future maybe in disk, shard, partition
-    data_block: Vec<u8>,
-    topic_offsets: HashMap<K, VecDeque<u64>>,
-    topic_committed_offsets: HashMap<K, u64>,
-    current_offset: u64,
+    log: SegmentLog,
+    topic_offsets: HashMap<K, KeyLog>,
+    /// Committed offsets of every consumer group; commits without a group go
+    /// to the group named "".
+    topic_committed_offsets: HashMap<String, HashMap<K, u64>>,
+    // 下一个要压缩的段从这个offset开始找，轮流压缩所有封存段
+    compact_cursor: u64,
     _mark: PhantomData<V>,
 }
 
-// Record format
-//    u32         u64                V
-// +--------+-------------+-----------------------------+
-// | length |   offset    |       value                 |
-// +--------+-------------+-----------------------------+
-struct Record<V> {
+// Record format, version 2
+//    u32       u64      u8     u32      u8        u64        u32              u32
+// +--------+--------+-------+-------+-------+-------------+---------+-----+-----------+-------+
+// | length | offset | magic |  crc  | attrs | [timestamp] | key len | key | value len | value |
+// +--------+--------+-------+-------+-------+-------------+---------+-----+-----------+-------+
+// `length` counts the whole record, itself included. `crc` is the CRC32C of
+// every other byte of the record. The timestamp is only there when bit 0 of
+// `attrs` is set. Version 1 has no `value len`; its value is the rest of the
+// record.
+struct Record<K, V> {
     offset: u64,
+    /// Unix millis of the append.
+    timestamp: Option<u64>,
+    key: K,
     value: V,
 }
 
 const U32_LEN: usize = std::mem::size_of::<u32>();
 const U64_LEN: usize = std::mem::size_of::<u64>();
+const HEADER_LEN: usize = U32_LEN + U64_LEN;
+const MAGIC: u8 = 2;
+// magic 和 crc 在header后面，crc算的时候跳过自己
+const CRC_POS: usize = HEADER_LEN + 1;
+const ATTRS_POS: usize = CRC_POS + U32_LEN;
+const HAS_TIMESTAMP: u8 = 1;
+
+/// Fields of an encoded record whose checksum matched.
+struct RecordView<'a> {
+    #[allow(unused)]
+    timestamp: Option<u64>,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> RecordView<'a> {
+    fn parse(record: &'a [u8]) -> Result<Self> {
+        let malformed = |what: &str| GanError::Normal(format!("malformed record: {what}"));
+        let body = record
+            .get(ATTRS_POS..)
+            .ok_or_else(|| malformed("too short"))?;
+        let version = record[HEADER_LEN];
+        if !(1..=MAGIC).contains(&version) {
+            return Err(malformed(&format!("unknown version {version}")));
+        }
+        let (_, crc) = to_u32(&record[CRC_POS..]).unwrap();
+        if crc != crc32c(&[&record[..CRC_POS], body]) {
+            return Err(malformed("checksum mismatch"));
+        }
+        let (&attrs, mut data) = body.split_first().ok_or_else(|| malformed("too short"))?;
+        let mut timestamp = None;
+        if attrs & HAS_TIMESTAMP != 0 {
+            let (rest, ts) = to_u64(data).ok_or_else(|| malformed("too short"))?;
+            timestamp = Some(ts);
+            data = rest;
+        }
+        let (data, key_length) = to_u32(data).ok_or_else(|| malformed("too short"))?;
+        let key = data
+            .get(..key_length as usize)
+            .ok_or_else(|| malformed("key past the end"))?;
+        let mut value = &data[key_length as usize..];
+        if version >= 2 {
+            let (rest, value_length) = to_u32(value).ok_or_else(|| malformed("too short"))?;
+            if rest.len() != value_length as usize {
+                return Err(malformed("value length doesn't match the record"));
+            }
+            value = rest;
+        }
+        Ok(RecordView {
+            timestamp,
+            key,
+            value,
+        })
+    }
+}
+
+// CRC32C (Castagnoli)，反射多项式 0x82F63B78
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32C of `chunks` laid end to end.
+fn crc32c(chunks: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for chunk in chunks {
+        for &byte in *chunk {
+            crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+/// Offsets of one key. A key counts its own offsets 0, 1, 2, … like a Kafka
+/// partition; `positions[i]` is where the record with key offset `start + i`
+/// sits in the shared log, so a poll reads it without searching. Retention
+/// moves `start` forward; compaction leaves gaps marked `DROPPED`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct KeyLog {
+    start: u64,
+    positions: VecDeque<Position>,
+}
+
+/// `(key offset, value)` of the polled records of every key.
+type Polled<K, V> = HashMap<K, Vec<(u64, V)>>;
+
+/// Log offset of a record and its byte position within its segment.
+type Position = (u64, u64);
+
+/// Byte position of a record compaction removed; its key offset stays taken.
+const DROPPED: u64 = u64::MAX;
+
+impl KeyLog {
+    fn next_offset(&self) -> u64 {
+        self.start + self.positions.len() as u64
+    }
+
+    fn push(&mut self, position: Position) -> u64 {
+        self.positions.push_back(position);
+        self.next_offset() - 1
+    }
+
+    /// `(key offset, position)` of the records from `offset` on.
+    fn from(&self, offset: u64) -> impl Iterator<Item = (u64, Position)> + '_ {
+        let skip = (offset.saturating_sub(self.start) as usize).min(self.positions.len());
+        (self.start + skip as u64..).zip(self.positions.range(skip..).copied())
+    }
+
+    /// Forget the records below log offset `first`, which retention deleted.
+    fn trim(&mut self, first: u64) {
+        while self.positions.front().is_some_and(|(o, _)| *o < first) {
+            self.positions.pop_front();
+            self.start += 1;
+        }
+    }
+
+    /// Indices of `positions` whose log offset is in `range`.
+    fn within(&self, range: std::ops::Range<u64>) -> std::ops::Range<usize> {
+        let from = self.positions.partition_point(|(o, _)| *o < range.start);
+        let to = self.positions.partition_point(|(o, _)| *o < range.end);
+        from..to
+    }
+}
+
+/// `topic_offsets` as of `next_offset`, written whenever a segment is rolled,
+/// deleted or compacted, so recovery only has to scan the active segment.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "K: Serialize + DeserializeOwned + Eq + std::hash::Hash")]
+struct Checkpoint<K> {
+    next_offset: u64,
+    topic_offsets: Vec<(K, KeyLog)>,
+}
 
 impl<K, V> KafkaStorage<K, V>
 where
-    K: Clone + IntoBytes + Eq + std::hash::Hash,
+    K: Clone + IntoBytes + FromBytes + Eq + std::hash::Hash + Serialize + DeserializeOwned,
     V: IntoBytes + FromBytes,
 {
-    // 以为commit_offset会清除掉offset之前的data，降低存储大小，原来题意是想commit_offset只是更新下committed offset，在list_commit_offset使用吐出去。
-    // 这个删除api看将来是否用得到
-    #[allow(dead_code)]
-    fn remove_data(&mut self, offsets: HashMap<K, u64>) -> Result<()> {
-        if offsets.is_empty() {
-            return Ok(());
+    /// Open the log described by `config`, rebuilding the offsets of every key
+    /// from the last checkpoint plus the records written after it.
+    fn open(config: LogConfig) -> Result<Self> {
+        let dir = config.dir.clone();
+        let log = SegmentLog::open(config)?;
+        let mut storage = KafkaStorage {
+            log,
+            topic_offsets: HashMap::new(),
+            topic_committed_offsets: HashMap::new(),
+            compact_cursor: 0,
+            _mark: PhantomData,
+        };
+        let Some(dir) = dir else {
+            return Ok(storage);
+        };
+        let mut next_offset = 0;
+        if let Some(checkpoint) = read_json::<Checkpoint<K>>(&dir.join(CHECKPOINT_FILE))? {
+            next_offset = checkpoint.next_offset;
+            storage.topic_offsets = checkpoint.topic_offsets.into_iter().collect();
         }
-        for (k, offset) in offsets.into_iter() {
-            if let Some(queue) = self.topic_offsets.get_mut(&k) {
-                while let Some(ofs) = queue.pop_front() {
-                    if ofs > offset {
-                        queue.push_front(ofs);
-                        break;
-                    }
-                    Self::remove_record(&mut self.data_block, ofs)?;
-                }
-                if queue.is_empty() {
-                    self.topic_offsets.remove(&k);
-                }
-            }
+        // 检查点之后retention可能又删过段
+        let first = storage.log.first_offset();
+        for key_log in storage.topic_offsets.values_mut() {
+            key_log.trim(first);
         }
-        self.data_block.shrink_to_fit();
-        Ok(())
+        for (position, record) in storage.log.scan(next_offset)? {
+            let (key, _) = Self::decode(&record)?;
+            storage.topic_offsets.entry(key).or_default().push(position);
+        }
+        if let Some(committed) =
+            read_json::<Vec<(String, Vec<(K, u64)>)>>(&dir.join(COMMITTED_FILE))?
+        {
+            storage.topic_committed_offsets = committed
+                .into_iter()
+                .map(|(group, offsets)| (group, offsets.into_iter().collect()))
+                .collect();
+        }
+        Ok(storage)
     }
 
+    /// Append `value` and return its offset within `key`.
     fn send(&mut self, key: K, value: V) -> Result<u64> {
-        let offset = self.current_offset;
-        let record = Record { offset, value };
-        let r = record.to_le_bytes();
-        let record_length = r.len() + U32_LEN;
-        self.data_block
-            .extend_from_slice((record_length as u32).to_le_bytes().as_slice());
-        self.data_block.extend_from_slice(r.as_slice());
-        let queue = self.topic_offsets.entry(key).or_insert(Default::default());
-        queue.push_back(offset);
-        self.current_offset += record_length as u64;
-        Ok(offset)
-    }
-
-    fn poll(&mut self, offsets: HashMap<K, u64>) -> Result<HashMap<K, Vec<(u64, V)>>> {
-        let mut result = HashMap::new();
-        // 没数据或者传入空offsets都直接返回
-        if self.data_block.len() <= U32_LEN || offsets.is_empty() {
-            return Ok(result);
-        }
-        for (k, offset) in offsets.into_iter() {
-            if let Some(queue) = self.topic_offsets.get_mut(&k) {
-                // 找出大于等于offset的所有offset
-                let index = queue.binary_search(&offset).unwrap_or_else(|near| near);
-                // 没找到而且都比队列offset的大，跳过
-                if index >= queue.len() {
-                    continue;
+        let record = Record {
+            offset: self.log.next_offset(),
+            timestamp: self.log.config.timestamps.then(unix_millis),
+            key: key.clone(),
+            value,
+        }
+        .to_le_bytes();
+        if self.log.roll_if_full(record.len() as u64)? {
+            self.checkpoint()?;
+        }
+        let position = self.log.append(&record)?;
+        Ok(self.topic_offsets.entry(key).or_default().push(position))
+    }
+
+    /// Records of every key from its requested offset on, taken in log order
+    /// until `limits` are reached. Returns whether records were left out.
+    fn poll(&self, offsets: HashMap<K, u64>, limits: &PollLimits) -> Result<(Polled<K, V>, bool)> {
+        let mut cursors: Vec<_> = offsets
+            .into_iter()
+            .filter_map(|(k, offset)| {
+                let key_log = self.topic_offsets.get(&k)?;
+                let records = key_log.from(offset).filter(|(_, (_, pos))| *pos != DROPPED);
+                Some((k, records.peekable(), Vec::new(), 0))
+            })
+            .collect();
+        // 按log offset合并各个key，限额先给最早的record
+        let mut heap: BinaryHeap<_> = cursors
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, (_, records, _, _))| Some(Reverse((records.peek()?.1 .0, i))))
+            .collect();
+        let (mut messages, mut bytes) = (0, 0);
+        let mut more = false;
+        while let Some(Reverse((_, i))) = heap.pop() {
+            let (_, records, values, key_bytes) = &mut cursors[i];
+            let (offset, position) = records.next().expect("heap entries have a record");
+            // 找不到说明压缩删掉了它，只是崩溃前没来得及写检查点
+            let Some(record) = self.log.read(position)? else {
+                if let Some((_, (next, _))) = records.peek() {
+                    heap.push(Reverse((*next, i)));
                 }
-                let offset_slice = queue.make_contiguous();
-                let Some(values) = Self::parse_records(&self.data_block, &offset_slice[index..]) else {
-                    return Err(GanError::Normal("解析record时候根据offset没找到, 本应该一定有的".to_string()));
-                };
-                result.entry(k).or_insert(values);
+                continue;
+            };
+            let size = record.len();
+            // 第一条总是给，不然一条大record会让消费者永远卡住
+            if limits.max_messages.is_some_and(|max| messages >= max)
+                || limits
+                    .max_bytes
+                    .is_some_and(|max| messages > 0 && bytes + size > max)
+            {
+                more = true;
+                break;
+            }
+            if limits
+                .max_messages_per_key
+                .is_some_and(|max| values.len() >= max)
+                || limits
+                    .max_bytes_per_key
+                    .is_some_and(|max| !values.is_empty() && *key_bytes + size > max)
+            {
+                more = true;
+                continue;
+            }
+            values.push((offset, Self::decode(&record)?.1));
+            *key_bytes += size;
+            messages += 1;
+            bytes += size;
+            if let Some((_, (next, _))) = records.peek() {
+                heap.push(Reverse((*next, i)));
             }
         }
-        Ok(result)
+        let result = cursors
+            .into_iter()
+            .filter(|(_, _, values, _)| !values.is_empty())
+            .map(|(k, _, values, _)| (k, values))
+            .collect();
+        Ok((result, more))
     }
 
-    fn commit_offsets(&mut self, offsets: HashMap<K, u64>) -> Result<()> {
+    /// Commit `offsets` for `group`, all or none. A committed offset only
+    /// moves forward (precondition-failed otherwise) and can't pass the end
+    /// of its key (key-does-not-exist otherwise).
+    fn commit_offsets(&mut self, group: &str, offsets: HashMap<K, u64>) -> Result<()> {
         if offsets.is_empty() {
             return Ok(());
         }
-        for (k, offset) in offsets.into_iter() {
-            self.topic_committed_offsets.insert(k, offset);
+        let committed = self.topic_committed_offsets.get(group);
+        for (k, &offset) in &offsets {
+            let end = self.topic_offsets.get(k).map_or(0, KeyLog::next_offset);
+            if offset > end {
+                return Err(GanError::Rpc {
+                    code: 20,
+                    text: format!("offset {offset} is past the end of the key at {end}"),
+                });
+            }
+            if let Some(&current) = committed.and_then(|c| c.get(k)) {
+                if offset < current {
+                    return Err(GanError::Rpc {
+                        code: 22,
+                        text: format!("offset {offset} is behind the committed {current}"),
+                    });
+                }
+            }
+        }
+        self.topic_committed_offsets
+            .entry(group.to_string())
+            .or_default()
+            .extend(offsets);
+        if let Some(dir) = &self.log.config.dir {
+            let committed: Vec<(_, Vec<_>)> = self
+                .topic_committed_offsets
+                .iter()
+                .map(|(group, offsets)| (group, offsets.iter().collect()))
+                .collect();
+            write_json(&dir.join(COMMITTED_FILE), &committed)?;
         }
         Ok(())
     }
 
-    fn list_committed_offsets(&mut self, keys: Vec<K>) -> HashMap<K, u64> {
+    fn list_committed_offsets(&mut self, group: &str, keys: Vec<K>) -> HashMap<K, u64> {
+        let Some(committed) = self.topic_committed_offsets.get(group) else {
+            return HashMap::new();
+        };
         keys.into_iter()
             .filter_map(|k| {
-                let offset = self.topic_committed_offsets.get(&k).map(|&o| o);
+                let offset = committed.get(&k).copied();
                 Some(k).zip(offset)
             })
             .collect()
     }
 
-    // TODO: 优化删除记录，现在remove字节，然后收紧，效率O(n^2)
-    fn remove_record(data_block: &mut Vec<u8>, offset: u64) -> Result<()> {
-        let mut datas = data_block.as_slice();
-        let mut idx = 0;
-        loop {
-            let Some((data, length)) =  to_u32(datas) else {
-                break;
-            };
-            let Some((data, ofs)) = to_u64(data) else {
-                break;
-            };
-            let value_length = length as usize - U32_LEN - U64_LEN;
-            if data.len() < value_length {
-                break;
+    /// Delete the oldest sealed segments that are past the retention time,
+    /// over the byte budget, or hold only records every key has committed
+    /// past. Whole segments go at once, so nothing is copied or shifted.
+    fn enforce_retention(&mut self, config: &RetentionConfig) -> Result<()> {
+        let sealed = &self.log.segments[..self.log.segments.len() - 1];
+        let mut expired = 0;
+        if let Some(max_age) = config.max_age {
+            let cutoff = unix_millis().saturating_sub(max_age.as_millis() as u64);
+            expired = sealed.partition_point(|s| s.sealed_at <= cutoff);
+        }
+        if let Some(max_bytes) = config.max_bytes {
+            let mut total = self.log.size();
+            let mut n = 0;
+            while n < sealed.len() && total > max_bytes {
+                total -= sealed[n].size;
+                n += 1;
             }
-            if ofs != offset {
-                datas = &data[value_length..];
-                idx += length as usize;
-                continue;
+            expired = expired.max(n);
+        }
+        if config.committed {
+            let consumed = self.consumed_offset();
+            // 段的结尾就是下一个段的开头
+            let n = self.log.segments[1..].partition_point(|s| s.base_offset <= consumed);
+            expired = expired.max(n);
+        }
+        if expired == 0 {
+            return Ok(());
+        }
+        self.log.remove_front(expired)?;
+        let first = self.log.first_offset();
+        for key_log in self.topic_offsets.values_mut() {
+            key_log.trim(first);
+        }
+        self.checkpoint()
+    }
+
+    /// Log offset below which every group has committed every record. A key
+    /// some group never committed pins its oldest record.
+    fn consumed_offset(&self) -> u64 {
+        self.topic_offsets
+            .iter()
+            .filter_map(|(k, key_log)| {
+                let committed = self
+                    .topic_committed_offsets
+                    .values()
+                    .map(|offsets| offsets.get(k).copied().unwrap_or(0))
+                    .min()
+                    .unwrap_or(0);
+                // 已提交的那条自己保留，消费者从这里继续
+                let i = committed.saturating_sub(key_log.start) as usize;
+                key_log.positions.get(i).map(|(offset, _)| *offset)
+            })
+            .min()
+            .unwrap_or(self.log.next_offset())
+    }
+
+    /// Compaction of the next sealed segment in turn: drop every record that
+    /// has a later one with the same key.
+    fn compaction_job(&mut self) -> Option<CompactionJob> {
+        let sealed = &self.log.segments[..self.log.segments.len() - 1];
+        let next = sealed
+            .iter()
+            .find(|s| s.base_offset >= self.compact_cursor)
+            .or(sealed.first())?;
+        self.compact_cursor = next.base_offset + 1;
+        let latest = self
+            .topic_offsets
+            .iter()
+            .filter_map(|(k, key_log)| {
+                let (offset, _) = key_log.positions.back()?;
+                Some((k.clone().to_le_bytes().as_slice().to_vec(), *offset))
+            })
+            .collect();
+        Some(CompactionJob {
+            base_offset: next.base_offset,
+            source: match &next.data {
+                SegmentData::Memory(data) => CompactionSource::Memory(data.clone()),
+                SegmentData::File(_) => CompactionSource::File(Segment::log_path(
+                    self.log.config.dir.as_ref()?,
+                    next.base_offset,
+                )),
+            },
+            latest,
+            index_interval: self.log.config.index_interval,
+            sealed_at: next.sealed_at,
+        })
+    }
+
+    /// Swap a compacted segment in and point the keys at the records' new
+    /// positions. A segment retention deleted in the meantime is ignored.
+    fn finish_compaction(&mut self, compacted: Compacted) -> Result<()> {
+        if compacted.dropped == 0 {
+            return Ok(());
+        }
+        let base = compacted.base_offset;
+        let Some(end) = self.log.replace_sealed(compacted.segment)? else {
+            return Ok(());
+        };
+        let moved: HashMap<u64, u64> = compacted.kept.into_iter().collect();
+        for key_log in self.topic_offsets.values_mut() {
+            for i in key_log.within(base..end) {
+                let (offset, pos) = &mut key_log.positions[i];
+                *pos = moved.get(offset).copied().unwrap_or(DROPPED);
             }
-            let _ = data_block.drain(idx..idx + length as usize);
+        }
+        eprintln!(
+            "kafka compaction: dropped {} records from segment {base}",
+            compacted.dropped
+        );
+        self.checkpoint()
+    }
+
+    fn checkpoint(&self) -> Result<()> {
+        let Some(dir) = &self.log.config.dir else {
             return Ok(());
+        };
+        let checkpoint = Checkpoint {
+            next_offset: self.log.next_offset(),
+            topic_offsets: self
+                .topic_offsets
+                .iter()
+                .map(|(k, key_log)| (k.clone(), key_log.clone()))
+                .collect(),
+        };
+        write_json(&dir.join(CHECKPOINT_FILE), &checkpoint)
+    }
+
+    /// Key and value of a record read from the log, header included.
+    fn decode(record: &[u8]) -> Result<(K, V)> {
+        let view = RecordView::parse(record)?;
+        Ok((K::from_bytes(view.key)?, V::from_bytes(view.value)?))
+    }
+}
+
+const CHECKPOINT_FILE: &str = "topic-offsets.checkpoint";
+const COMMITTED_FILE: &str = "committed-offsets.json";
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    write_atomic(path, &serde_json::to_vec(value)?)
+}
+
+/// When appends reach the disk.
+#[derive(Debug, Clone, Copy)]
+enum FsyncPolicy {
+    /// Before every `send` is acknowledged.
+    Always,
+    /// After every n records; a crash loses at most the last n - 1.
+    Every(u64),
+    /// Whenever the OS writes back; a crash of the node process alone loses
+    /// nothing, a crash of the host may.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = GanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            n => n
+                .parse()
+                .map(|n: u64| FsyncPolicy::Every(n.max(1)))
+                .map_err(|_| GanError::Normal(format!("unknown fsync policy {n}"))),
         }
+    }
+}
 
-        Err(GanError::Normal("根据offset没找到对应数据块".to_string()))
+#[derive(Debug, Clone)]
+struct LogConfig {
+    /// Where segments live; `None` keeps them in memory.
+    dir: Option<PathBuf>,
+    segment_bytes: u64,
+    /// Bytes between two entries of a segment's sparse index.
+    index_interval: u64,
+    fsync: FsyncPolicy,
+    /// Stamp every record with the time of its append.
+    timestamps: bool,
+}
+
+impl LogConfig {
+    /// `KAFKA_DATA_DIR`, `KAFKA_SEGMENT_BYTES`, `KAFKA_INDEX_INTERVAL`,
+    /// `KAFKA_FSYNC` (`always`, `never` or a record count) and
+    /// `KAFKA_RECORD_TIMESTAMPS` (0 leaves them out).
+    fn from_env() -> Self {
+        LogConfig {
+            dir: std::env::var("KAFKA_DATA_DIR").ok().map(PathBuf::from),
+            segment_bytes: env_or("KAFKA_SEGMENT_BYTES", 1 << 20),
+            index_interval: env_or("KAFKA_INDEX_INTERVAL", 4096),
+            fsync: env_or("KAFKA_FSYNC", FsyncPolicy::Always),
+            timestamps: env_or("KAFKA_RECORD_TIMESTAMPS", 1u8) != 0,
+        }
     }
+}
 
-    fn parse_records(mut data_block: &[u8], offsets: &[u64]) -> Option<Vec<(u64, V)>> {
-        if offsets.is_empty() {
-            return None;
+/// What `maintain` cleans up, checked every `KAFKA_MAINTENANCE_MS`.
+#[derive(Debug, Clone)]
+struct RetentionConfig {
+    /// Delete sealed segments sealed longer ago than this.
+    max_age: Option<Duration>,
+    /// Delete the oldest sealed segments while the log is bigger than this.
+    max_bytes: Option<u64>,
+    /// Delete sealed segments whose records every key has committed past.
+    committed: bool,
+    /// Keep only the latest record of each key in sealed segments. Polls see
+    /// gaps in the key offsets where records were dropped.
+    compact: bool,
+    interval: Duration,
+}
+
+impl RetentionConfig {
+    /// `KAFKA_RETENTION_MS` and `KAFKA_RETENTION_BYTES` (0 keeps everything),
+    /// `KAFKA_RETENTION_COMMITTED=1`, `KAFKA_COMPACT=1` and
+    /// `KAFKA_MAINTENANCE_MS`.
+    fn from_env() -> Self {
+        let max_age = env_or("KAFKA_RETENTION_MS", 0);
+        let max_bytes = env_or("KAFKA_RETENTION_BYTES", 0);
+        RetentionConfig {
+            max_age: (max_age > 0).then(|| Duration::from_millis(max_age)),
+            max_bytes: (max_bytes > 0).then_some(max_bytes),
+            committed: env_or("KAFKA_RETENTION_COMMITTED", 0u8) != 0,
+            compact: env_or("KAFKA_COMPACT", 0u8) != 0,
+            interval: Duration::from_millis(env_or("KAFKA_MAINTENANCE_MS", 1000)),
         }
-        let mut result = Vec::new();
-        loop {
-            let Some((data, length)) =  to_u32(data_block) else {
-                break;
-            };
-            let Some((data, ofs)) = to_u64(data) else {
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_bytes.is_some() || self.committed || self.compact
+    }
+}
+
+/// Append-only log split into segments of about `segment_bytes`, named by the
+/// offset of their first record. Offsets are dense: the n-th record ever
+/// appended has offset n. Only the last segment is written to; the others are
+/// sealed with their sparse index saved next to them.
+struct SegmentLog {
+    config: LogConfig,
+    segments: Vec<Segment>,
+    next_offset: u64,
+    unsynced: u64,
+}
+
+struct Segment {
+    base_offset: u64,
+    size: u64,
+    // 稀疏索引 (offset, 段内位置)，每隔 index_interval 字节一条。读record直接用
+    // KeyLog 里的位置，这个只在恢复时找已封存段的最后一条，以及位置过期时兜底
+    index: Vec<(u64, u64)>,
+    /// Unix millis when the segment was sealed, 0 while it's active.
+    sealed_at: u64,
+    data: SegmentData,
+}
+
+enum SegmentData {
+    // 封存后压缩线程和主线程共享，不用拷贝
+    Memory(Arc<Vec<u8>>),
+    File(File),
+}
+
+impl SegmentData {
+    fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        match self {
+            SegmentData::Memory(data) => Arc::make_mut(data).extend_from_slice(bytes),
+            SegmentData::File(file) => file.write_all(bytes)?,
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            SegmentData::Memory(data) => {
+                let pos = pos as usize;
+                let src = data
+                    .get(pos..pos + buf.len())
+                    .ok_or_else(|| GanError::Normal("read past the end of segment".to_string()))?;
+                buf.copy_from_slice(src);
+            }
+            SegmentData::File(file) => file.read_exact_at(buf, pos)?,
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        if let SegmentData::File(file) = self {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn log_path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{base_offset:020}.log"))
+    }
+
+    fn index_path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{base_offset:020}.index"))
+    }
+
+    fn compacted_path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{base_offset:020}{COMPACTED_SUFFIX}"))
+    }
+
+    fn create(config: &LogConfig, base_offset: u64) -> Result<Self> {
+        let data = match &config.dir {
+            Some(dir) => SegmentData::File(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .read(true)
+                    .open(Self::log_path(dir, base_offset))?,
+            ),
+            None => SegmentData::Memory(Arc::default()),
+        };
+        Ok(Segment {
+            base_offset,
+            size: 0,
+            index: Vec::new(),
+            sealed_at: 0,
+            data,
+        })
+    }
+
+    /// Open a segment file. Sealed segments load their saved index; the active
+    /// one, or a sealed one without index, is verified, and everything from
+    /// the first incomplete or corrupt record on (a crash in the middle of an
+    /// append) is cut off.
+    /// Returns the segment and the offset after its last record.
+    fn recover(
+        config: &LogConfig,
+        dir: &Path,
+        base_offset: u64,
+        sealed: bool,
+    ) -> Result<(Self, u64)> {
+        let path = Self::log_path(dir, base_offset);
+        let file = OpenOptions::new().append(true).read(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // 封存之后段文件不再改动，修改时间就是封存时间
+        let sealed_at = match metadata.modified()?.duration_since(std::time::UNIX_EPOCH) {
+            Ok(d) if sealed => d.as_millis() as u64,
+            _ => 0,
+        };
+        let mut segment = Segment {
+            base_offset,
+            size: metadata.len(),
+            index: Vec::new(),
+            sealed_at,
+            data: SegmentData::File(file),
+        };
+        if sealed {
+            if let Ok(index) = std::fs::read(Self::index_path(dir, base_offset)) {
+                segment.index = index
+                    .chunks_exact(2 * U64_LEN)
+                    .map(|entry| {
+                        let (_, offset) = to_u64(entry).unwrap();
+                        let (_, pos) = to_u64(&entry[U64_LEN..]).unwrap();
+                        (offset, pos)
+                    })
+                    .collect();
+                let next_offset = segment.last_offset()?.map_or(base_offset, |o| o + 1);
+                return Ok((segment, next_offset));
+            }
+        }
+        let mut data = vec![0; segment.size as usize];
+        segment.data.read_at(0, &mut data)?;
+        let verified = verify(&data);
+        let mut next_offset = base_offset;
+        for &(pos, offset) in &verified.records {
+            segment.note(config, offset, pos);
+            next_offset = offset + 1;
+        }
+        let valid = verified.valid;
+        if let Some(problem) = verified.problem {
+            eprintln!(
+                "kafka log: {problem} at byte {valid} of {}, cutting off the last {} bytes",
+                path.display(),
+                segment.size - valid,
+            );
+            if let SegmentData::File(file) = &segment.data {
+                file.set_len(valid)?;
+                file.sync_all()?;
+            }
+            segment.size = valid;
+        }
+        Ok((segment, next_offset))
+    }
+
+    /// Add an index entry for the record `offset` at `pos` if the last one is
+    /// `index_interval` bytes back.
+    fn note(&mut self, config: &LogConfig, offset: u64, pos: u64) {
+        let due = self
+            .index
+            .last()
+            .is_none_or(|(_, last)| pos - last >= config.index_interval);
+        if due {
+            self.index.push((offset, pos));
+        }
+    }
+
+    /// Returns the byte position of the record.
+    fn append(&mut self, config: &LogConfig, offset: u64, record: &[u8]) -> Result<u64> {
+        self.data.append(record)?;
+        let pos = self.size;
+        self.note(config, offset, pos);
+        self.size += record.len() as u64;
+        Ok(pos)
+    }
+
+    /// Write the sparse index next to the segment and flush the segment.
+    fn seal(&self, config: &LogConfig) -> Result<()> {
+        let Some(dir) = &config.dir else {
+            return Ok(());
+        };
+        self.data.sync()?;
+        let mut index = Vec::with_capacity(self.index.len() * 2 * U64_LEN);
+        for (offset, pos) in &self.index {
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&pos.to_le_bytes());
+        }
+        let mut file = File::create(Self::index_path(dir, self.base_offset))?;
+        file.write_all(&index)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn header_at(&self, pos: u64) -> Result<(u32, u64)> {
+        let mut header = [0; HEADER_LEN];
+        self.data.read_at(pos, &mut header)?;
+        let (rest, length) = to_u32(&header).unwrap();
+        let offset = u64::from_le_bytes(rest.try_into().unwrap());
+        Ok((length, offset))
+    }
+
+    fn read(&self, offset: u64, pos: u64) -> Result<Option<Vec<u8>>> {
+        if pos + HEADER_LEN as u64 > self.size {
+            return self.find(offset);
+        }
+        let (length, ofs) = self.header_at(pos)?;
+        if ofs != offset || pos + length as u64 > self.size {
+            return self.find(offset);
+        }
+        let mut record = vec![0; length as usize];
+        self.data.read_at(pos, &mut record)?;
+        Ok(Some(record))
+    }
+
+    /// Look `offset` up through the sparse index, for positions taken before
+    /// the segment was compacted.
+    fn find(&self, offset: u64) -> Result<Option<Vec<u8>>> {
+        let i = self.index.partition_point(|(o, _)| *o <= offset);
+        let mut pos = match i.checked_sub(1) {
+            Some(i) => self.index[i].1,
+            None => 0,
+        };
+        while pos + HEADER_LEN as u64 <= self.size {
+            let (length, ofs) = self.header_at(pos)?;
+            if ofs > offset || (length as usize) < HEADER_LEN {
                 break;
+            }
+            if ofs == offset {
+                let mut record = vec![0; length as usize];
+                self.data.read_at(pos, &mut record)?;
+                return Ok(Some(record));
+            }
+            pos += length as u64;
+        }
+        Ok(None)
+    }
+
+    fn last_offset(&self) -> Result<Option<u64>> {
+        let Some(&(mut offset, mut pos)) = self.index.last() else {
+            return Ok(None);
+        };
+        while pos + HEADER_LEN as u64 <= self.size {
+            let (length, ofs) = self.header_at(pos)?;
+            offset = ofs;
+            pos += length as u64;
+        }
+        Ok(Some(offset))
+    }
+}
+
+const COMPACTED_SUFFIX: &str = ".log.compacted";
+
+/// Copy of a sealed segment to compact on another thread.
+struct CompactionJob {
+    base_offset: u64,
+    source: CompactionSource,
+    /// Log offset of the latest record of every key, by key bytes.
+    latest: HashMap<Vec<u8>, u64>,
+    index_interval: u64,
+    sealed_at: u64,
+}
+
+enum CompactionSource {
+    Memory(Arc<Vec<u8>>),
+    File(PathBuf),
+}
+
+struct Compacted {
+    base_offset: u64,
+    dropped: usize,
+    /// `(log offset, new position)` of the records that stay.
+    kept: Vec<(u64, u64)>,
+    segment: Segment,
+}
+
+impl CompactionJob {
+    fn run(self) -> Result<Compacted> {
+        let data = match &self.source {
+            CompactionSource::Memory(data) => data.clone(),
+            CompactionSource::File(path) => Arc::new(std::fs::read(path)?),
+        };
+        let config = LogConfig {
+            dir: None,
+            segment_bytes: u64::MAX,
+            index_interval: self.index_interval,
+            fsync: FsyncPolicy::Never,
+            timestamps: false,
+        };
+        let mut segment = Segment::create(&config, self.base_offset)?;
+        let mut compacted = Vec::with_capacity(data.len());
+        let mut kept = Vec::new();
+        let mut dropped = 0;
+        for (_, offset, record) in records(&data) {
+            let superseded = RecordView::parse(record)
+                .ok()
+                .and_then(|view| self.latest.get(view.key))
+                .is_some_and(|latest| *latest > offset);
+            if superseded {
+                dropped += 1;
+                continue;
+            }
+            let pos = compacted.len() as u64;
+            segment.note(&config, offset, pos);
+            compacted.extend_from_slice(record);
+            kept.push((offset, pos));
+        }
+        segment.size = compacted.len() as u64;
+        segment.sealed_at = self.sealed_at;
+        if dropped == 0 {
+            return Ok(Compacted {
+                base_offset: self.base_offset,
+                dropped,
+                kept,
+                segment,
+            });
+        }
+        segment.data = match &self.source {
+            CompactionSource::Memory(_) => SegmentData::Memory(Arc::new(compacted)),
+            CompactionSource::File(path) => {
+                let dir = path.parent().unwrap_or(Path::new("."));
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .read(true)
+                    .open(Segment::compacted_path(dir, self.base_offset))?;
+                file.write_all(&compacted)?;
+                // 保留原来的封存时间，按时间的retention重启后也照旧
+                file.set_modified(std::time::UNIX_EPOCH + Duration::from_millis(self.sealed_at))?;
+                file.sync_all()?;
+                SegmentData::File(file)
+            }
+        };
+        Ok(Compacted {
+            base_offset: self.base_offset,
+            dropped,
+            kept,
+            segment,
+        })
+    }
+}
+
+/// `(position, offset, record)` of every complete record in `data`.
+fn records(mut data: &[u8]) -> impl Iterator<Item = (u64, u64, &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let (rest, length) = to_u32(data)?;
+        let (_, offset) = to_u64(rest)?;
+        let length = length as usize;
+        if length < HEADER_LEN || data.len() < length {
+            return None;
+        }
+        let record = &data[..length];
+        data = &data[length..];
+        let item = (pos, offset, record);
+        pos += length as u64;
+        Some(item)
+    })
+}
+
+/// Result of checking a segment's records front to back.
+struct Verified {
+    /// `(position, offset)` of the intact records.
+    records: Vec<(u64, u64)>,
+    /// Bytes up to the end of the last intact record.
+    valid: u64,
+    /// What stopped the check before the end of the data.
+    problem: Option<String>,
+}
+
+/// Check every record of `data` for completeness, checksum and increasing
+/// offsets, stopping at the first one that fails.
+fn verify(data: &[u8]) -> Verified {
+    let mut verified = Verified {
+        records: Vec::new(),
+        valid: 0,
+        problem: None,
+    };
+    for (pos, offset, record) in records(data) {
+        if let Err(e) = RecordView::parse(record) {
+            verified.problem = Some(format!("record {offset}: {e}"));
+            return verified;
+        }
+        if verified
+            .records
+            .last()
+            .is_some_and(|(_, last)| *last >= offset)
+        {
+            verified.problem = Some(format!("record {offset} out of order"));
+            return verified;
+        }
+        verified.records.push((pos, offset));
+        verified.valid = pos + record.len() as u64;
+    }
+    if verified.valid < data.len() as u64 {
+        verified.problem = Some("incomplete record".to_string());
+    }
+    verified
+}
+
+impl SegmentLog {
+    fn open(config: LogConfig) -> Result<Self> {
+        let mut log = SegmentLog {
+            segments: Vec::new(),
+            next_offset: 0,
+            unsynced: 0,
+            config,
+        };
+        let Some(dir) = log.config.dir.clone() else {
+            log.segments.push(Segment::create(&log.config, 0)?);
+            return Ok(log);
+        };
+        std::fs::create_dir_all(&dir)?;
+        let mut bases = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
             };
-            let value_length = length as usize - U32_LEN - U64_LEN;
-            if data.len() < value_length {
-                break;
+            if name.ends_with(COMPACTED_SUFFIX) {
+                // 压缩到一半崩溃了，原来的段还在
+                std::fs::remove_file(&path)?;
+            } else if let Some(base) = name.strip_suffix(".log").and_then(|b| b.parse().ok()) {
+                bases.push(base);
             }
-            let offset = offsets[result.len()];
-            if ofs != offset {
-                data_block = &data[value_length..];
+        }
+        bases.sort_unstable();
+        for (i, &base) in bases.iter().enumerate() {
+            let sealed = i + 1 < bases.len();
+            let (segment, next_offset) = Segment::recover(&log.config, &dir, base, sealed)?;
+            log.segments.push(segment);
+            log.next_offset = next_offset;
+        }
+        if log.segments.is_empty() {
+            log.segments.push(Segment::create(&log.config, 0)?);
+        }
+        Ok(log)
+    }
+
+    fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    fn active(&mut self) -> &mut Segment {
+        self.segments
+            .last_mut()
+            .expect("log always has an active segment")
+    }
+
+    /// Seal the active segment and start a new one if a record of `len` bytes
+    /// doesn't fit anymore. Returns whether a new segment was started.
+    fn roll_if_full(&mut self, len: u64) -> Result<bool> {
+        let active = self
+            .segments
+            .last()
+            .expect("log always has an active segment");
+        if active.size == 0 || active.size + len <= self.config.segment_bytes {
+            return Ok(false);
+        }
+        active.seal(&self.config)?;
+        self.active().sealed_at = unix_millis();
+        let segment = Segment::create(&self.config, self.next_offset)?;
+        self.segments.push(segment);
+        self.unsynced = 0;
+        Ok(true)
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<Position> {
+        let offset = self.next_offset;
+        let config = self.config.clone();
+        let active = self.active();
+        let pos = active.append(&config, offset, record)?;
+        self.next_offset += 1;
+        self.unsynced += 1;
+        let sync = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.active().data.sync()?;
+            self.unsynced = 0;
+        }
+        Ok((offset, pos))
+    }
+
+    fn first_offset(&self) -> u64 {
+        self.segments[0].base_offset
+    }
+
+    /// Bytes of all segments.
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum()
+    }
+
+    /// Delete the `n` oldest segments. The active one always stays.
+    fn remove_front(&mut self, n: usize) -> Result<()> {
+        let n = n.min(self.segments.len() - 1);
+        for segment in self.segments.drain(..n) {
+            let Some(dir) = &self.config.dir else {
                 continue;
+            };
+            std::fs::remove_file(Segment::log_path(dir, segment.base_offset))?;
+            match std::fs::remove_file(Segment::index_path(dir, segment.base_offset)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
             }
-            let value = V::from_bytes(&data[..value_length]);
-            data_block = &data[value_length..];
-            result.push((offset, value));
-            // 已经找完成就返回
-            if offsets.len() == result.len() {
-                return Some(result);
+        }
+        Ok(())
+    }
+
+    /// Put a compacted copy in place of the sealed segment with the same base
+    /// offset. Returns the offset the segment ends at, or `None` if it's gone.
+    fn replace_sealed(&mut self, segment: Segment) -> Result<Option<u64>> {
+        let base = segment.base_offset;
+        let sealed = self.segments.len() - 1;
+        let Some(i) = self.segments[..sealed]
+            .iter()
+            .position(|s| s.base_offset == base)
+        else {
+            if let Some(dir) = &self.config.dir {
+                std::fs::remove_file(Segment::compacted_path(dir, base))?;
             }
+            return Ok(None);
+        };
+        if let Some(dir) = &self.config.dir {
+            std::fs::rename(
+                Segment::compacted_path(dir, base),
+                Segment::log_path(dir, base),
+            )?;
         }
-        //TODO: 要判断offset长度跟results长度一样，一样才拿全了，否则要么offsets又问题，要么datablock又问题，应该返回result，
-        // 不过现在主要测试分布式系统日志存储，所以后期优化。
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+        segment.seal(&self.config)?;
+        self.segments[i] = segment;
+        Ok(Some(self.segments[i + 1].base_offset))
+    }
+
+    fn segment_of(&self, offset: u64) -> Option<&Segment> {
+        let i = self.segments.partition_point(|s| s.base_offset <= offset);
+        self.segments.get(i.checked_sub(1)?)
+    }
+
+    /// The record at `position`, header included.
+    fn read(&self, (offset, pos): Position) -> Result<Option<Vec<u8>>> {
+        match self.segment_of(offset) {
+            Some(segment) => segment.read(offset, pos),
+            None => Ok(None),
         }
     }
+
+    /// Every record from `offset` on.
+    fn scan(&self, offset: u64) -> Result<Vec<(Position, Vec<u8>)>> {
+        let first = self
+            .segments
+            .partition_point(|s| s.base_offset <= offset)
+            .saturating_sub(1);
+        let mut result = Vec::new();
+        for segment in &self.segments[first..] {
+            let mut data = vec![0; segment.size as usize];
+            segment.data.read_at(0, &mut data)?;
+            result.extend(
+                records(&data)
+                    .filter(|(_, o, _)| *o >= offset)
+                    .map(|(pos, o, record)| ((o, pos), record.to_vec())),
+            );
+        }
+        Ok(result)
+    }
 }
 
 fn to_u32(mut data: &[u8]) -> Option<(&[u8], u32)> {
-    if data.len() <= U32_LEN {
+    if data.len() < U32_LEN {
         return None;
     }
     let length = u32::from_le_bytes((&data[..U32_LEN]).try_into().unwrap());
@@ -269,7 +1437,7 @@ fn to_u32(mut data: &[u8]) -> Option<(&[u8], u32)> {
 }
 
 fn to_u64(mut data: &[u8]) -> Option<(&[u8], u64)> {
-    if data.len() <= U64_LEN {
+    if data.len() < U64_LEN {
         return None;
     }
     let length = u64::from_le_bytes((&data[..U64_LEN]).try_into().unwrap());
@@ -283,34 +1451,83 @@ fn to_u64(mut data: &[u8]) -> Option<(&[u8], u64)> {
 enum Payload {
     Send {
         key: String,
-        msg: u64,
+        msg: serde_json::Value,
     },
     SendOk {
         offset: u64,
     },
     Poll {
         offsets: HashMap<String, u64>,
+        #[serde(flatten)]
+        limits: PollLimits,
     },
     PollOk {
-        msgs: HashMap<String, Vec<(u64, u64)>>,
+        msgs: HashMap<String, Vec<(u64, serde_json::Value)>>,
+        /// Some records past the returned ones were left out by the limits.
+        more: bool,
     },
     CommitOffsets {
         offsets: HashMap<String, u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     #[default]
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, u64>,
     },
+    /// Join `group` as `member` subscribed to `keys`, or change the
+    /// subscription; the reply carries the keys assigned to `member`.
+    JoinGroup {
+        group: String,
+        member: String,
+        keys: Vec<String>,
+    },
+    JoinGroupOk {
+        generation: u64,
+        keys: Vec<String>,
+    },
+    /// Keeps `member` in the group; a changed generation in the reply means
+    /// the keys were rebalanced.
+    Heartbeat {
+        group: String,
+        member: String,
+    },
+    HeartbeatOk {
+        generation: u64,
+        keys: Vec<String>,
+    },
+    LeaveGroup {
+        group: String,
+        member: String,
+    },
+    LeaveGroupOk,
     Error {
         code: u8,
         text: String,
     },
 }
 
+/// Optional bounds on a poll response. Bytes count whole stored records. The
+/// first record of a response, and of each key for the per-key byte limit,
+/// is returned even when it alone is over the limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PollLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_messages: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_messages_per_key: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes_per_key: Option<usize>,
+}
+
 trait IntoBytes: Sized {
     type Output: AsSlice;
     fn to_le_bytes(self) -> Self::Output;
@@ -331,12 +1548,37 @@ macro_rules! impl_into_bytes {
 
 impl_into_bytes!(u32, u64);
 
-impl<V: IntoBytes> IntoBytes for Record<V> {
+impl<K: IntoBytes, V: IntoBytes> IntoBytes for Record<K, V> {
     type Output = Vec<u8>;
     fn to_le_bytes(self) -> Self::Output {
-        let mut result = Vec::new();
+        let key = self.key.to_le_bytes();
+        let value = self.value.to_le_bytes();
+        let timestamp_len = if self.timestamp.is_some() { U64_LEN } else { 0 };
+        let length = ATTRS_POS
+            + 1
+            + timestamp_len
+            + U32_LEN
+            + key.as_slice().len()
+            + U32_LEN
+            + value.as_slice().len();
+        let mut result = Vec::with_capacity(length);
+        result.extend_from_slice((length as u32).to_le_bytes().as_slice());
         result.extend_from_slice(self.offset.to_le_bytes().as_slice());
-        result.extend_from_slice(self.value.to_le_bytes().as_slice());
+        result.push(MAGIC);
+        result.extend_from_slice(&[0; U32_LEN]);
+        match self.timestamp {
+            Some(timestamp) => {
+                result.push(HAS_TIMESTAMP);
+                result.extend_from_slice(timestamp.to_le_bytes().as_slice());
+            }
+            None => result.push(0),
+        }
+        result.extend_from_slice((key.as_slice().len() as u32).to_le_bytes().as_slice());
+        result.extend_from_slice(key.as_slice());
+        result.extend_from_slice((value.as_slice().len() as u32).to_le_bytes().as_slice());
+        result.extend_from_slice(value.as_slice());
+        let crc = crc32c(&[&result[..CRC_POS], &result[ATTRS_POS..]]);
+        result[CRC_POS..ATTRS_POS].copy_from_slice(&crc.to_le_bytes());
         result
     }
 }
@@ -348,13 +1590,51 @@ impl IntoBytes for String {
     }
 }
 
+impl IntoBytes for Vec<u8> {
+    type Output = Vec<u8>;
+    fn to_le_bytes(self) -> Self::Output {
+        self
+    }
+}
+
+impl IntoBytes for serde_json::Value {
+    type Output = Vec<u8>;
+    fn to_le_bytes(self) -> Self::Output {
+        // Value 的 key 都是字符串，序列化不会失败
+        serde_json::to_vec(&self).expect("json value always serializes")
+    }
+}
+
+/// Decoding of bytes written by `IntoBytes`; fails on bytes that aren't a
+/// valid `Self`, e.g. a value stored by a node run with another value type.
 trait FromBytes: Sized {
-    fn from_bytes(slice: &[u8]) -> Self;
+    fn from_bytes(slice: &[u8]) -> Result<Self>;
 }
 
 impl FromBytes for u64 {
-    fn from_bytes(slice: &[u8]) -> Self {
-        u64::from_le_bytes((&slice[..U64_LEN]).try_into().unwrap())
+    fn from_bytes(slice: &[u8]) -> Result<Self> {
+        let bytes = slice
+            .try_into()
+            .map_err(|_| GanError::Normal(format!("{} bytes are not a u64", slice.len())))?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl FromBytes for String {
+    fn from_bytes(slice: &[u8]) -> Result<Self> {
+        String::from_utf8(slice.to_vec()).map_err(|e| GanError::Normal(e.to_string()))
+    }
+}
+
+impl FromBytes for Vec<u8> {
+    fn from_bytes(slice: &[u8]) -> Result<Self> {
+        Ok(slice.to_vec())
+    }
+}
+
+impl FromBytes for serde_json::Value {
+    fn from_bytes(slice: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(slice)?)
     }
 }
 
@@ -1,46 +1,173 @@
+use std::collections::VecDeque;
 use std::io::StdoutLock;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use rustengan::*;
 
 fn main() -> Result<()> {
-    main_loop::<_, UniqueNode, _, _>(())?;
+    main_loop::<_, UniqueNode, _, _>(env_or("UNIQUE_ID_FORMAT", IdFormat::String))?;
     Ok(())
 }
 
+/// Default from `UNIQUE_ID_FORMAT`, overridable per request with `format`.
+#[derive(Debug, Clone, Copy)]
+enum IdFormat {
+    /// `<node>-<n>`, `n` from a `Reservation` when `UNIQUE_PERSIST_DIR` is set.
+    String,
+    /// 64-bit integer, see `Snowflake`.
+    Snowflake,
+    /// 26 character Crockford base32 ULID.
+    Ulid,
+    /// RFC 9562 version 7 UUID, see `UuidV7`.
+    UuidV7,
+    /// Dense integers handed out from blocks leased from lin-kv, see `Leases`.
+    Leased,
+}
+
+impl FromStr for IdFormat {
+    type Err = GanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "string" => Ok(IdFormat::String),
+            "snowflake" => Ok(IdFormat::Snowflake),
+            "ulid" => Ok(IdFormat::Ulid),
+            "uuidv7" => Ok(IdFormat::UuidV7),
+            "leased" => Ok(IdFormat::Leased),
+            _ => Err(GanError::Normal(format!("unknown id format {s}"))),
+        }
+    }
+}
+
 struct UniqueNode {
     id: usize,
     node_id: String,
+    format: IdFormat,
+    snowflake: Snowflake,
+    // 同一毫秒内递增随机部分，保证本节点单调
+    ulid: ulid::Generator,
+    uuid: UuidV7,
+    leases: Leases,
+    sequence: Option<Reservation>,
 }
-impl Node<(), Payload> for UniqueNode {
-    fn from_init(_: (), init: Init, _: std::sync::mpsc::Sender<Event<Payload>>) -> Result<Self>
+impl Node<IdFormat, Payload, InjectedPayload> for UniqueNode {
+    fn from_init(
+        format: IdFormat,
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> Result<Self>
     where
         Self: Sized,
     {
+        // 租约请求丢了的话靠这个重发
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(100));
+            if tx.send(Event::Injected(InjectedPayload::Tick)).is_err() {
+                break;
+            }
+        });
+        let index = init
+            .node_ids
+            .iter()
+            .position(|n| *n == init.node_id)
+            .ok_or_else(|| GanError::Normal(format!("{} is not in node_ids", init.node_id)))?;
         Ok(UniqueNode {
             id: 1,
+            format,
+            snowflake: Snowflake::new(index as u64)?,
+            ulid: ulid::Generator::new(),
+            uuid: UuidV7::default(),
+            leases: Leases {
+                active: matches!(format, IdFormat::Leased),
+                ..Leases::from_env()
+            },
+            sequence: match std::env::var("UNIQUE_PERSIST_DIR") {
+                Ok(dir) => Some(Reservation::open(
+                    PathBuf::from(dir).join(format!("unique-ids-{}", init.node_id)),
+                    env_or("UNIQUE_RESERVE", 1000),
+                )?),
+                Err(_) => None,
+            },
             node_id: init.node_id,
         })
     }
     fn step(
         &mut self,
-        input: Event<Payload>,
+        input: Event<Payload, InjectedPayload>,
         output: &mut StdoutLock,
-        _: &Receiver<Event<Payload>>,
+        _: &Receiver<Event<Payload, InjectedPayload>>,
     ) -> Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+        let input = match input {
+            Event::Message(input) => input,
+            // 没用过 leased 格式的节点不去租，免得每个节点都白占一段id
+            Event::Injected(InjectedPayload::Tick) if !self.leases.active => return Ok(()),
+            Event::Injected(InjectedPayload::Tick) => {
+                if self
+                    .leases
+                    .request
+                    .is_some_and(|(_, sent)| sent.elapsed() > self.leases.timeout)
+                {
+                    self.leases.request = None;
+                }
+                return self.lease(output);
+            }
+            Event::EOF => return Ok(()),
         };
+        let lease_reply = input.body.in_reply_to.is_some()
+            && input.body.in_reply_to == self.leases.request.map(|(id, _)| id);
+        if lease_reply {
+            self.leases.request = None;
+            return self.on_lease(input.body.payload, output);
+        }
         let mut reply = input.into_reply(Some(&mut self.id));
         match reply.body.payload {
-            Payload::Generate => {
-                let guid = format!("{}-{}", self.node_id, self.id);
+            Payload::Generate { ref format } => {
+                let format = match format.as_deref().map(str::parse).transpose() {
+                    Ok(format) => format.unwrap_or(self.format),
+                    Err(e) => {
+                        // malformed-request
+                        reply.body.payload = Payload::Error {
+                            code: 12,
+                            text: e.to_string(),
+                        };
+                        return reply.send(output);
+                    }
+                };
+                let guid = match format {
+                    IdFormat::String => {
+                        let n = match &mut self.sequence {
+                            Some(sequence) => sequence.next()?,
+                            None => self.id as u64,
+                        };
+                        Guid::Text(format!("{}-{}", self.node_id, n))
+                    }
+                    IdFormat::Snowflake => Guid::Number(self.snowflake.next()),
+                    IdFormat::Ulid => Guid::Text(
+                        self.ulid
+                            .generate()
+                            .map_err(|e| GanError::Normal(e.to_string()))?
+                            .to_string(),
+                    ),
+                    IdFormat::UuidV7 => Guid::Text(self.uuid.next()),
+                    IdFormat::Leased => {
+                        self.leases.active = true;
+                        self.leases.waiting.push_back(reply);
+                        return self.lease(output);
+                    }
+                };
                 reply.body.payload = Payload::GenerateOk { guid };
                 reply.send(output)?;
             }
-            Payload::GenerateOk { .. } => {
+            // 超时之后才到的租约回复，那一段id就跳过了
+            Payload::ReadOk { .. } | Payload::CasOk | Payload::Error { .. } => (),
+            Payload::GenerateOk { .. } | Payload::Read { .. } | Payload::Cas { .. } => {
                 return Err(GanError::Normal(
                     "we should never receive generate_ok".to_string(),
                 ))
@@ -51,13 +178,314 @@ impl Node<(), Payload> for UniqueNode {
     }
 }
 
+const HIGH_WATER_KEY: &str = "unique-ids-high-water";
+
+/// Blocks of ids leased from lin-kv: a lease moves the shared high-water key
+/// from `n` to `n + block` with a CAS and the node owns `n..n + block` from
+/// then on, so ids are unique without asking anyone, dense apart from what
+/// is left of the blocks of a node when it stops, and roughly ordered by
+/// lease time. The next block is leased once fewer than `prefetch` ids are
+/// left, so a partition only stops a node after it used up what it holds.
+/// Nodes only start leasing once ids are requested in the leased format, or
+/// right away when it's the default.
+struct Leases {
+    active: bool,
+    block: u64,
+    prefetch: u64,
+    timeout: Duration,
+    blocks: VecDeque<Range<u64>>,
+    // 正在进行的 read 或 cas，以及 cas 的 from
+    request: Option<(usize, Instant)>,
+    pending_from: Option<u64>,
+    // 还没有id可发的generate请求的回复
+    waiting: VecDeque<Message<Payload>>,
+}
+
+impl Leases {
+    /// Block size from `UNIQUE_LEASE_BLOCK`, prefetch threshold from
+    /// `UNIQUE_LEASE_PREFETCH`.
+    fn from_env() -> Self {
+        let block = env_or("UNIQUE_LEASE_BLOCK", 1000u64).max(1);
+        Leases {
+            active: false,
+            block,
+            prefetch: env_or("UNIQUE_LEASE_PREFETCH", block / 5),
+            timeout: Duration::from_millis(env_or("UNIQUE_LEASE_TIMEOUT_MS", 1000)),
+            blocks: VecDeque::new(),
+            request: None,
+            pending_from: None,
+            waiting: VecDeque::new(),
+        }
+    }
+
+    fn next(&mut self) -> Option<u64> {
+        loop {
+            let block = self.blocks.front_mut()?;
+            if let Some(id) = block.next() {
+                return Some(id);
+            }
+            self.blocks.pop_front();
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.blocks.iter().map(|b| b.end - b.start).sum()
+    }
+}
+
+impl UniqueNode {
+    /// Answer waiting requests from the leased blocks and start leasing the
+    /// next block when running low.
+    fn lease(&mut self, output: &mut StdoutLock) -> Result<()> {
+        while !self.leases.waiting.is_empty() {
+            let Some(id) = self.leases.next() else {
+                break;
+            };
+            let mut reply = self.leases.waiting.pop_front().unwrap();
+            reply.body.payload = Payload::GenerateOk {
+                guid: Guid::Number(id),
+            };
+            reply.send(&mut *output)?;
+        }
+        let running_low = self.leases.remaining() < self.leases.prefetch.max(1)
+            || !self.leases.waiting.is_empty();
+        if running_low && self.leases.request.is_none() {
+            self.send_lease_request(
+                Payload::Read {
+                    key: HIGH_WATER_KEY.to_string(),
+                },
+                output,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn on_lease(&mut self, payload: Payload, output: &mut StdoutLock) -> Result<()> {
+        let high_water = match payload {
+            Payload::ReadOk { value } => value,
+            // key-does-not-exist: 第一次租
+            Payload::Error { code: 20, .. } => 0,
+            Payload::CasOk => {
+                // 请求发出时记下的from
+                let from = self.leases.pending_from.take().unwrap_or_default();
+                self.leases.blocks.push_back(from..from + self.leases.block);
+                return self.lease(output);
+            }
+            // precondition-failed: 被别的节点抢先了，重新读
+            Payload::Error { code: 22, .. } => return self.lease(output),
+            Payload::Error { code, text } => {
+                eprintln!("lease error({code}): {text}");
+                return Ok(());
+            }
+            _ => {
+                return Err(GanError::Normal(
+                    "should not exist invalid response".to_string(),
+                ))
+            }
+        };
+        self.leases.pending_from = Some(high_water);
+        self.send_lease_request(
+            Payload::Cas {
+                key: HIGH_WATER_KEY.to_string(),
+                from: high_water,
+                to: high_water + self.leases.block,
+                create_if_not_exists: high_water == 0,
+            },
+            output,
+        )
+    }
+
+    fn send_lease_request(&mut self, payload: Payload, output: &mut StdoutLock) -> Result<()> {
+        self.leases.request = Some((self.id, Instant::now()));
+        let message = Message {
+            src: self.node_id.clone(),
+            dst: "lin-kv".to_string(),
+            body: Body {
+                id: Some(self.id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.id += 1;
+        message.send(output)
+    }
+}
+
+/// Sequence that survives restarts of a node: before handing out `n` it makes
+/// sure a high-water mark above `n` is on disk, reserving `step` numbers at a
+/// time, and after a restart it continues from the saved mark. A restart
+/// skips whatever was left of the last reservation.
+struct Reservation {
+    path: PathBuf,
+    step: u64,
+    next: u64,
+    reserved: u64,
+}
+
+impl Reservation {
+    fn open(path: PathBuf, step: u64) -> Result<Self> {
+        let reserved = match std::fs::read_to_string(&path) {
+            Ok(mark) => mark
+                .trim()
+                .parse()
+                .map_err(|e| GanError::Normal(format!("bad mark in {}: {e}", path.display())))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Reservation {
+            path,
+            step: step.max(1),
+            next: reserved,
+            reserved,
+        })
+    }
+
+    fn next(&mut self) -> Result<u64> {
+        if self.next >= self.reserved {
+            let reserved = self.next + self.step;
+            write_atomic(&self.path, reserved.to_string().as_bytes())?;
+            self.reserved = reserved;
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+}
+
+// 2023-01-01T00:00:00Z
+const SNOWFLAKE_EPOCH_MS: u64 = 1_672_531_200_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+/// Twitter-style snowflake: 41 bits of milliseconds since `SNOWFLAKE_EPOCH_MS`,
+/// 10 bits of node index and a 12 bit sequence within the millisecond, so ids
+/// of one node are strictly increasing and ids of all nodes roughly sort by
+/// creation time.
+///
+/// The timestamp never goes backwards: when the clock regresses, or more than
+/// 4096 ids are taken within one millisecond, ids keep being drawn from the
+/// last used millisecond and then the following ones, and the clock catches up
+/// again later.
+struct Snowflake {
+    node: u64,
+    // 上一次读到的时钟，只用来发现时钟回拨
+    clock: u64,
+    last_ms: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    fn new(node: u64) -> Result<Self> {
+        if node >= 1 << NODE_BITS {
+            return Err(GanError::Normal(format!(
+                "snowflake ids support at most {} nodes",
+                1 << NODE_BITS
+            )));
+        }
+        Ok(Snowflake {
+            node,
+            clock: 0,
+            last_ms: 0,
+            sequence: 0,
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        let now = unix_millis().saturating_sub(SNOWFLAKE_EPOCH_MS);
+        if now < self.clock {
+            eprintln!("snowflake: clock went back {}ms", self.clock - now);
+        }
+        self.clock = now;
+        if now > self.last_ms {
+            self.last_ms = now;
+            self.sequence = 0;
+        } else {
+            self.sequence += 1;
+            if self.sequence == 1 << SEQUENCE_BITS {
+                self.last_ms += 1;
+                self.sequence = 0;
+            }
+        }
+        (self.last_ms << (NODE_BITS + SEQUENCE_BITS)) | (self.node << SEQUENCE_BITS) | self.sequence
+    }
+}
+
+/// UUIDv7: 48 bits of unix milliseconds, then a 12 bit counter in `rand_a`
+/// (RFC 9562 section 6.2, method 1) and 62 random bits. The counter starts at
+/// a random value below 2048 in every new millisecond and is incremented for
+/// every id within it; when it runs out the next millisecond is borrowed, as
+/// when the clock goes back, so ids of one node are strictly increasing.
+#[derive(Default)]
+struct UuidV7 {
+    last_ms: u64,
+    counter: u16,
+}
+
+impl UuidV7 {
+    fn next(&mut self) -> String {
+        let mut rng = rand::thread_rng();
+        let now = unix_millis();
+        if now > self.last_ms {
+            self.last_ms = now;
+            self.counter = rng.gen_range(0..1 << 11);
+        } else {
+            self.counter += 1;
+            if self.counter == 1 << 12 {
+                self.last_ms += 1;
+                self.counter = rng.gen_range(0..1 << 11);
+            }
+        }
+        let rand_b: u64 = rng.gen();
+        let high = (self.last_ms << 16) | (0x7 << 12) | self.counter as u64;
+        let low = (0b10 << 62) | (rand_b >> 2);
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0xffff,
+            low >> 48,
+            low & 0xffff_ffff_ffff
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Guid {
+    Text(String),
+    Number(u64),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Generate,
+    Generate {
+        #[serde(default)]
+        format: Option<String>,
+    },
     GenerateOk {
         #[serde(rename = "id")]
-        guid: String,
+        guid: Guid,
+    },
+    Error {
+        code: u8,
+        text: String,
     },
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: u64,
+    },
+    Cas {
+        key: String,
+        from: u64,
+        to: u64,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
+
+enum InjectedPayload {
+    Tick,
 }
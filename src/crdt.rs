@@ -0,0 +1,837 @@
+//! State-based CRDTs that converge by merging whole states, plus delta-state
+//! mutators: every mutation returns a (small) value of the same type holding
+//! just the change, which can be shipped and merged like a full state.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{Body, Message};
+
+pub trait Crdt: Clone + Default + Serialize + DeserializeOwned {
+    /// Join `other` into `self`. Must be commutative, associative and
+    /// idempotent, so replicas that merged the same states agree.
+    fn merge(&mut self, other: &Self);
+}
+
+/// Bounds for the elements of the set-like CRDTs.
+pub trait Element: Clone + Eq + Hash + Serialize + DeserializeOwned {}
+
+impl<T> Element for T where T: Clone + Eq + Hash + Serialize + DeserializeOwned {}
+
+/// Maps are serialized as `[[key, value], ...]`: JSON object keys must be
+/// strings, and payloads are flattened into the message body, which can't
+/// turn a string key back into an integer.
+mod pairs {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Grow-only set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent, bound = "T: Element")]
+pub struct GSet<T: Element>(HashSet<T>);
+
+impl<T: Element> Default for GSet<T> {
+    fn default() -> Self {
+        GSet(HashSet::new())
+    }
+}
+
+impl<T: Element> GSet<T> {
+    pub fn insert(&mut self, value: T) -> Self {
+        self.0.insert(value.clone());
+        GSet(HashSet::from([value]))
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.0.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T: Element> Crdt for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.0.extend(other.0.iter().cloned());
+    }
+}
+
+/// Two-phase set: an element can be added and removed once, removal wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: Element")]
+pub struct TwoPSet<T: Element> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Element> Default for TwoPSet<T> {
+    fn default() -> Self {
+        TwoPSet {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Element> TwoPSet<T> {
+    pub fn insert(&mut self, value: T) -> Self {
+        TwoPSet {
+            added: self.added.insert(value),
+            removed: GSet::default(),
+        }
+    }
+
+    /// Removing an element that was never added is a no-op.
+    pub fn remove(&mut self, value: &T) -> Self {
+        if !self.added.contains(value) {
+            return TwoPSet::default();
+        }
+        TwoPSet {
+            added: GSet::default(),
+            removed: self.removed.insert(value.clone()),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.added.contains(value) && !self.removed.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added.iter().filter(|v| !self.removed.contains(v))
+    }
+}
+
+impl<T: Element> Crdt for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+}
+
+/// One grow-only count per replica, merged by max.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter(HashMap<String, u64>);
+
+impl GCounter {
+    pub fn increment(&mut self, replica: &str, n: u64) -> Self {
+        let count = self.0.entry(replica.to_string()).or_default();
+        *count += n;
+        GCounter(HashMap::from([(replica.to_string(), *count)]))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    /// The share of `replica`.
+    pub fn get(&self, replica: &str) -> u64 {
+        self.0.get(replica).copied().unwrap_or(0)
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (replica, &count) in &other.0 {
+            let slot = self.0.entry(replica.clone()).or_default();
+            *slot = (*slot).max(count);
+        }
+    }
+}
+
+/// Counter that can go down: a `GCounter` for increments and one for decrements.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn add(&mut self, replica: &str, delta: i64) -> Self {
+        let mut result = PNCounter::default();
+        if delta >= 0 {
+            result.increments = self.increments.increment(replica, delta.unsigned_abs());
+        } else {
+            result.decrements = self.decrements.increment(replica, delta.unsigned_abs());
+        }
+        result
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+
+    pub fn increments(&self) -> &GCounter {
+        &self.increments
+    }
+
+    pub fn decrements(&self) -> &GCounter {
+        &self.decrements
+    }
+}
+
+impl Crdt for PNCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+/// Last-writer-wins register. Ties on the timestamp go to the larger replica id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    timestamp: u64,
+    replica: String,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        LwwRegister {
+            value: None,
+            timestamp: 0,
+            replica: String::new(),
+        }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    /// Writes with a timestamp older than the current one are ignored.
+    pub fn set(&mut self, replica: &str, timestamp: u64, value: T) -> Self {
+        let write = LwwRegister {
+            value: Some(value),
+            timestamp,
+            replica: replica.to_string(),
+        };
+        if write.wins_over(self) {
+            *self = write.clone();
+        }
+        write
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    fn wins_over(&self, other: &Self) -> bool {
+        (self.timestamp, &self.replica) > (other.timestamp, &other.replica)
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Crdt for LwwRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if other.wins_over(self) {
+            *self = other.clone();
+        }
+    }
+}
+
+/// A unique event: the `counter`-th mutation made by `replica`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub replica: String,
+    pub counter: u64,
+}
+
+/// Every dot a replica has observed: a contiguous prefix per replica, plus the
+/// dots that arrived out of order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CausalContext {
+    compact: HashMap<String, u64>,
+    cloud: HashSet<Dot>,
+}
+
+impl CausalContext {
+    pub fn contains(&self, dot: &Dot) -> bool {
+        self.compact.get(&dot.replica).copied().unwrap_or(0) >= dot.counter
+            || self.cloud.contains(dot)
+    }
+
+    /// The next dot of `replica`, which must be the local replica: its own
+    /// dots are always contiguous.
+    pub fn next(&mut self, replica: &str) -> Dot {
+        let counter = self.compact.entry(replica.to_string()).or_default();
+        *counter += 1;
+        Dot {
+            replica: replica.to_string(),
+            counter: *counter,
+        }
+    }
+
+    pub fn insert(&mut self, dot: Dot) {
+        self.cloud.insert(dot);
+        self.compact();
+    }
+
+    fn compact(&mut self) {
+        loop {
+            let before = self.cloud.len();
+            let compact = &mut self.compact;
+            self.cloud.retain(|dot| {
+                let counter = compact.entry(dot.replica.clone()).or_default();
+                if dot.counter == *counter + 1 {
+                    *counter += 1;
+                    false
+                } else {
+                    dot.counter > *counter
+                }
+            });
+            if self.cloud.len() == before {
+                return;
+            }
+        }
+    }
+}
+
+impl Crdt for CausalContext {
+    fn merge(&mut self, other: &Self) {
+        for (replica, &counter) in &other.compact {
+            let slot = self.compact.entry(replica.clone()).or_default();
+            *slot = (*slot).max(counter);
+        }
+        self.cloud.extend(other.cloud.iter().cloned());
+        self.compact();
+    }
+}
+
+/// Observed-remove set: a remove only cancels the adds it has seen, so a
+/// concurrent add wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "T: Element")]
+pub struct OrSet<T: Element> {
+    #[serde(with = "pairs")]
+    entries: HashMap<T, HashSet<Dot>>,
+    context: CausalContext,
+}
+
+impl<T: Element> Default for OrSet<T> {
+    fn default() -> Self {
+        OrSet {
+            entries: HashMap::new(),
+            context: CausalContext::default(),
+        }
+    }
+}
+
+impl<T: Element> OrSet<T> {
+    pub fn insert(&mut self, replica: &str, value: T) -> Self {
+        let dot = self.context.next(replica);
+        let mut delta = OrSet::default();
+        // 新的dot取代这个元素所有已经观察到的dot
+        for old in self.entries.remove(&value).into_iter().flatten() {
+            delta.context.insert(old);
+        }
+        delta.context.insert(dot.clone());
+        delta
+            .entries
+            .insert(value.clone(), HashSet::from([dot.clone()]));
+        self.entries.insert(value, HashSet::from([dot]));
+        delta
+    }
+
+    pub fn remove(&mut self, value: &T) -> Self {
+        let mut delta = OrSet::default();
+        for dot in self.entries.remove(value).into_iter().flatten() {
+            delta.context.insert(dot);
+        }
+        delta
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.entries.contains_key(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T: Element> Crdt for OrSet<T> {
+    fn merge(&mut self, other: &Self) {
+        let values: HashSet<_> = self
+            .entries
+            .keys()
+            .chain(other.entries.keys())
+            .cloned()
+            .collect();
+        let empty = HashSet::new();
+        for value in values {
+            let ours = self.entries.get(&value).unwrap_or(&empty);
+            let theirs = other.entries.get(&value).unwrap_or(&empty);
+            // 两边都有的dot保留，只有一边有的dot，如果另一边没见过就保留，见过说明已经被删了
+            let dots: HashSet<_> = ours
+                .iter()
+                .filter(|dot| theirs.contains(dot) || !other.context.contains(dot))
+                .chain(theirs.iter().filter(|dot| !self.context.contains(dot)))
+                .cloned()
+                .collect();
+            if dots.is_empty() {
+                self.entries.remove(&value);
+            } else {
+                self.entries.insert(value, dots);
+            }
+        }
+        self.context.merge(&other.context);
+    }
+}
+
+/// Map whose keys behave like an `OrSet` and whose values are CRDTs merged
+/// per key. A removed key keeps its value state, so adding it back shows the
+/// value merged with everything written before the remove.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "K: Element, V: Crdt")]
+pub struct OrMap<K: Element, V: Crdt> {
+    keys: OrSet<K>,
+    #[serde(with = "pairs")]
+    values: HashMap<K, V>,
+}
+
+impl<K: Element, V: Crdt> Default for OrMap<K, V> {
+    fn default() -> Self {
+        OrMap {
+            keys: OrSet::default(),
+            values: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Element, V: Crdt> OrMap<K, V> {
+    /// Apply a delta mutator of the value under `key`, adding the key if needed.
+    pub fn update(&mut self, replica: &str, key: K, f: impl FnOnce(&mut V) -> V) -> Self {
+        let keys = self.keys.insert(replica, key.clone());
+        let value = f(self.values.entry(key.clone()).or_default());
+        OrMap {
+            keys,
+            values: HashMap::from([(key, value)]),
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Self {
+        OrMap {
+            keys: self.keys.remove(key),
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if !self.keys.contains(key) {
+            return None;
+        }
+        self.values.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.keys
+            .iter()
+            .filter_map(|k| self.values.get(k).map(|v| (k, v)))
+    }
+}
+
+impl<K: Element, V: Crdt> Crdt for OrMap<K, V> {
+    fn merge(&mut self, other: &Self) {
+        self.keys.merge(&other.keys);
+        for (key, value) in &other.values {
+            match self.values.get_mut(key) {
+                Some(ours) => ours.merge(value),
+                None => {
+                    self.values.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Drives delta-state gossip of one CRDT among a fixed set of peers: local
+/// mutations are buffered as deltas and shipped every round, and every
+/// `full_every` rounds the whole state is sent instead, so peers that missed
+/// deltas (dropped messages, partitions) still converge.
+///
+/// The node owns the ticker and the payload variant carrying the state; on
+/// every tick it sends the messages from `round`, and on every gossip it
+/// receives it calls `merge`.
+pub struct Gossiper<C: Crdt> {
+    node_id: String,
+    peers: Vec<String>,
+    state: C,
+    delta: Option<C>,
+    rounds: u64,
+    full_every: u64,
+}
+
+impl<C: Crdt> Gossiper<C> {
+    /// `node_ids` may include the local node, which is skipped.
+    pub fn new(node_id: &str, node_ids: &[String]) -> Self {
+        Gossiper {
+            node_id: node_id.to_string(),
+            peers: node_ids.iter().filter(|n| *n != node_id).cloned().collect(),
+            state: C::default(),
+            delta: None,
+            rounds: 0,
+            full_every: 10,
+        }
+    }
+
+    /// Send the full state every `rounds` rounds; 1 disables delta shipping.
+    pub fn full_every(mut self, rounds: u64) -> Self {
+        self.full_every = rounds.max(1);
+        self
+    }
+
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// Apply a delta mutator to the local state and buffer its delta.
+    pub fn update(&mut self, mutate: impl FnOnce(&mut C) -> C) {
+        let delta = mutate(&mut self.state);
+        match &mut self.delta {
+            Some(buffered) => buffered.merge(&delta),
+            None => self.delta = Some(delta),
+        }
+    }
+
+    /// Merge a state or delta received from a peer.
+    pub fn merge(&mut self, remote: &C) {
+        self.state.merge(remote);
+    }
+
+    /// The gossip messages of this round, one per peer, or none when there's
+    /// nothing new to send.
+    pub fn round<P>(&mut self, wrap: impl Fn(C) -> P) -> Vec<Message<P>> {
+        self.rounds += 1;
+        let payload = if self.rounds.is_multiple_of(self.full_every) {
+            self.delta = None;
+            self.state.clone()
+        } else {
+            match self.delta.take() {
+                Some(delta) => delta,
+                None => return Vec::new(),
+            }
+        };
+        self.peers
+            .iter()
+            .map(|peer| Message {
+                src: self.node_id.clone(),
+                dst: peer.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: wrap(payload.clone()),
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+
+    fn merged<C: Crdt>(a: &C, b: &C) -> C {
+        let mut a = a.clone();
+        a.merge(b);
+        a
+    }
+
+    fn assert_laws<C: Crdt + PartialEq + Debug>(a: &C, b: &C, c: &C) {
+        assert_eq!(merged(a, b), merged(b, a), "merge must commute");
+        assert_eq!(
+            merged(&merged(a, b), c),
+            merged(a, &merged(b, c)),
+            "merge must be associative"
+        );
+        assert_eq!(merged(a, a), *a, "merge must be idempotent");
+        assert_eq!(merged(&merged(a, b), b), merged(a, b));
+    }
+
+    type Op<C> = Box<dyn Fn(&mut C) -> C>;
+
+    /// Replays `ops` on a fresh state, and checks that merging the deltas they
+    /// return into a second replica gives the same state.
+    fn assert_deltas<C: Crdt + PartialEq + Debug>(ops: Vec<Op<C>>) -> C {
+        let mut full = C::default();
+        let mut from_deltas = C::default();
+        let mut buffered = C::default();
+        for op in &ops {
+            let delta = op(&mut full);
+            from_deltas.merge(&delta);
+            buffered.merge(&delta);
+        }
+        assert_eq!(from_deltas, full, "deltas must add up to the full state");
+        assert_eq!(
+            merged(&C::default(), &buffered),
+            full,
+            "merged deltas must add up to the full state"
+        );
+        full
+    }
+
+    #[test]
+    fn gset_laws() {
+        let mut a = GSet::default();
+        let mut b = GSet::default();
+        let mut c = GSet::default();
+        a.insert(1);
+        a.insert(2);
+        b.insert(2);
+        b.insert(3);
+        c.insert(4);
+        assert_laws(&a, &b, &c);
+        let all = merged(&merged(&a, &b), &c);
+        assert_eq!(all.len(), 4);
+        assert_deltas::<GSet<u32>>(vec![Box::new(|s| s.insert(1)), Box::new(|s| s.insert(2))]);
+    }
+
+    #[test]
+    fn two_p_set_remove_is_final() {
+        let mut a = TwoPSet::default();
+        a.insert('x');
+        let mut b = a.clone();
+        b.remove(&'x');
+        // 删掉之后再加不回来，并发的加也一样
+        a.insert('x');
+        b.insert('x');
+        let mut c = TwoPSet::default();
+        c.insert('y');
+        assert_laws(&a, &b, &c);
+        let all = merged(&merged(&a, &b), &c);
+        assert!(!all.contains(&'x'));
+        assert!(all.contains(&'y'));
+        assert_deltas::<TwoPSet<char>>(vec![
+            Box::new(|s| s.insert('x')),
+            Box::new(|s| s.insert('y')),
+            Box::new(|s| s.remove(&'x')),
+        ]);
+    }
+
+    #[test]
+    fn counters() {
+        let mut a = PNCounter::default();
+        let mut b = PNCounter::default();
+        let mut c = PNCounter::default();
+        a.add("a", 5);
+        a.add("a", -2);
+        b.add("b", 7);
+        c.add("c", -1);
+        // c 已经看到过 a 的一部分
+        c.merge(&a);
+        a.add("a", 1);
+        assert_laws(&a, &b, &c);
+        assert_eq!(merged(&merged(&a, &b), &c).value(), 10);
+        let full = assert_deltas::<PNCounter>(vec![
+            Box::new(|s| s.add("a", 3)),
+            Box::new(|s| s.add("a", -1)),
+            Box::new(|s| s.add("b", 4)),
+            Box::new(|s| s.add("a", 2)),
+        ]);
+        assert_eq!(full.value(), 8);
+        assert_eq!(full.increments().get("a"), 5);
+        assert_eq!(full.decrements().get("a"), 1);
+    }
+
+    #[test]
+    fn lww_register_keeps_the_latest_write() {
+        let mut a = LwwRegister::default();
+        let mut b = LwwRegister::default();
+        let mut c = LwwRegister::default();
+        a.set("a", 1, 1);
+        b.set("b", 2, 2);
+        // 时间戳一样时 replica 大的赢
+        c.set("c", 2, 3);
+        assert_laws(&a, &b, &c);
+        assert_eq!(merged(&merged(&a, &b), &c).get(), Some(&3));
+        // 旧的写入被忽略
+        let mut stale = merged(&a, &b);
+        stale.set("a", 1, 4);
+        assert_eq!(stale.get(), Some(&2));
+        assert_deltas::<LwwRegister<u32>>(vec![
+            Box::new(|s| s.set("a", 3, 1)),
+            Box::new(|s| s.set("b", 2, 2)),
+            Box::new(|s| s.set("a", 4, 3)),
+        ]);
+    }
+
+    #[test]
+    fn causal_context_compacts_out_of_order_dots() {
+        let dot = |counter| Dot {
+            replica: "a".to_string(),
+            counter,
+        };
+        let mut context = CausalContext::default();
+        context.insert(dot(2));
+        assert!(context.contains(&dot(2)));
+        assert!(!context.contains(&dot(1)));
+        context.insert(dot(1));
+        assert_eq!(context.compact.get("a"), Some(&2));
+        assert!(context.cloud.is_empty());
+
+        let mut other = CausalContext::default();
+        other.insert(dot(4));
+        assert_laws(&context, &other, &CausalContext::default());
+        assert!(merged(&context, &other).contains(&dot(4)));
+        assert!(!merged(&context, &other).contains(&dot(3)));
+    }
+
+    #[test]
+    fn or_set_laws() {
+        let mut a = OrSet::default();
+        a.insert("a", 1);
+        a.insert("a", 2);
+        let mut b = a.clone();
+        b.remove(&1);
+        b.insert("b", 3);
+        let mut c = a.clone();
+        c.insert("c", 1);
+        c.remove(&2);
+        assert_laws(&a, &b, &c);
+        let all = merged(&merged(&a, &b), &c);
+        let mut values: Vec<_> = all.iter().copied().collect();
+        values.sort();
+        // b 删了 1 但 c 并发地又加了一次，2 被 c 删掉，a 没有并发的加
+        assert_eq!(values, vec![1, 3]);
+        assert_deltas::<OrSet<u32>>(vec![
+            Box::new(|s| s.insert("a", 1)),
+            Box::new(|s| s.insert("a", 2)),
+            Box::new(|s| s.remove(&1)),
+            Box::new(|s| s.insert("a", 1)),
+            Box::new(|s| s.remove(&2)),
+        ]);
+    }
+
+    #[test]
+    fn or_set_add_wins_over_concurrent_remove() {
+        let mut a = OrSet::default();
+        a.insert("a", 'x');
+        let mut b = a.clone();
+        b.remove(&'x');
+        a.insert("a", 'x');
+        a.merge(&b);
+        assert!(a.contains(&'x'), "a concurrent add must survive the remove");
+        b.merge(&a);
+        assert!(b.contains(&'x'));
+    }
+
+    #[test]
+    fn or_set_remove_cancels_observed_adds() {
+        let mut a = OrSet::default();
+        a.insert("a", 'x');
+        let mut b = OrSet::default();
+        b.merge(&a);
+        // b 看到过 a 的加，删除之后两边都没有了
+        let delta = b.remove(&'x');
+        a.merge(&delta);
+        assert!(!a.contains(&'x'));
+        assert!(a.is_empty());
+        // 删除只取消看到过的加，之后再加还在
+        a.insert("a", 'x');
+        b.merge(&a);
+        assert!(b.contains(&'x'));
+    }
+
+    #[test]
+    fn or_map_merges_values_per_key() {
+        let mut a: OrMap<char, PNCounter> = OrMap::default();
+        a.update("a", 'k', |v| v.add("a", 2));
+        let mut b = a.clone();
+        b.update("b", 'k', |v| v.add("b", 3));
+        b.update("b", 'j', |v| v.add("b", 1));
+        let mut c = a.clone();
+        c.remove(&'k');
+        assert_laws(&a, &b, &c);
+        let all = merged(&merged(&a, &b), &c);
+        // b 并发更新了 k，删除没看到那次更新，k 还在，值是两边合并的结果
+        assert_eq!(all.get(&'k').map(PNCounter::value), Some(5));
+        assert_eq!(all.get(&'j').map(PNCounter::value), Some(1));
+
+        let mut removed = merged(&a, &c);
+        assert_eq!(removed.get(&'k'), None);
+        removed.update("a", 'k', |v| v.add("a", 1));
+        assert_eq!(removed.get(&'k').map(PNCounter::value), Some(3));
+        assert_deltas::<OrMap<u32, GSet<u32>>>(vec![
+            Box::new(|s| s.update("a", 1, |v| v.insert(10))),
+            Box::new(|s| s.update("a", 2, |v| v.insert(20))),
+            Box::new(|s| s.remove(&1)),
+            Box::new(|s| s.update("a", 2, |v| v.insert(21))),
+        ]);
+    }
+
+    #[test]
+    fn crdts_round_trip_through_json() {
+        let mut set = OrSet::default();
+        set.insert("a", 7u64);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(serde_json::from_str::<OrSet<u64>>(&json).unwrap(), set);
+        let mut map: OrMap<u64, PNCounter> = OrMap::default();
+        map.update("a", 1, |v| v.add("a", -4));
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(
+            serde_json::from_str::<OrMap<u64, PNCounter>>(&json).unwrap(),
+            map
+        );
+    }
+
+    #[test]
+    fn gossiper_ships_deltas_and_periodic_full_states() {
+        let nodes = ["n0".to_string(), "n1".to_string()];
+        let mut n0: Gossiper<GCounter> = Gossiper::new("n0", &nodes).full_every(3);
+        let mut n1: Gossiper<GCounter> = Gossiper::new("n1", &nodes);
+        n0.update(|c| c.increment("n0", 2));
+        n0.update(|c| c.increment("n0", 1));
+        let messages = n0.round(|state| state);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].dst, "n1");
+        n1.merge(&messages[0].body.payload);
+        assert_eq!(n1.state().value(), 3);
+        // 没有新的修改就不发
+        assert!(n0.round(|state| state).is_empty());
+        // 第三轮发全量
+        let full = n0.round(|state| state);
+        assert_eq!(full[0].body.payload, *n0.state());
+    }
+}
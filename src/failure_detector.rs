@@ -0,0 +1,213 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::env_or;
+
+/// Phi accrual failure detector (Hayashibara et al.). Every message observed
+/// from a peer counts as a heartbeat; the suspicion level `phi` grows with the
+/// time since the last one, scaled by how regular that peer's arrivals have
+/// been so far. `phi = 1` means roughly a 10% chance the peer is still alive
+/// and just late, `phi = 8` roughly 0.000001%.
+#[derive(Debug)]
+pub struct FailureDetector {
+    threshold: f64,
+    window: usize,
+    min_std_dev: Duration,
+    acceptable_pause: Duration,
+    peers: HashMap<String, History>,
+}
+
+#[derive(Debug)]
+struct History {
+    last: Instant,
+    intervals: VecDeque<f64>,
+    sum: f64,
+    squared_sum: f64,
+}
+
+impl Default for FailureDetector {
+    fn default() -> Self {
+        FailureDetector::new(8.0)
+    }
+}
+
+impl FailureDetector {
+    pub fn new(threshold: f64) -> Self {
+        FailureDetector {
+            threshold,
+            window: 100,
+            min_std_dev: Duration::from_millis(100),
+            acceptable_pause: Duration::from_millis(0),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Threshold from `FAILURE_PHI_THRESHOLD`, extra tolerated silence from
+    /// `FAILURE_ACCEPTABLE_PAUSE_MS`.
+    pub fn from_env() -> Self {
+        FailureDetector::new(env_or("FAILURE_PHI_THRESHOLD", 8.0)).acceptable_pause(
+            Duration::from_millis(env_or("FAILURE_ACCEPTABLE_PAUSE_MS", 0)),
+        )
+    }
+
+    /// Number of inter-arrival samples kept per peer.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Lower bound of the standard deviation, so a peer with very regular
+    /// arrivals isn't suspected the moment it's a little late.
+    pub fn min_std_dev(mut self, min_std_dev: Duration) -> Self {
+        self.min_std_dev = min_std_dev;
+        self
+    }
+
+    /// Silence that is tolerated on top of the mean interval, e.g. for GC pauses.
+    pub fn acceptable_pause(mut self, acceptable_pause: Duration) -> Self {
+        self.acceptable_pause = acceptable_pause;
+        self
+    }
+
+    pub fn heartbeat(&mut self, peer: &str) {
+        self.heartbeat_at(peer, Instant::now());
+    }
+
+    pub fn heartbeat_at(&mut self, peer: &str, now: Instant) {
+        let Some(history) = self.peers.get_mut(peer) else {
+            self.peers.insert(
+                peer.to_string(),
+                History {
+                    last: now,
+                    intervals: VecDeque::new(),
+                    sum: 0.0,
+                    squared_sum: 0.0,
+                },
+            );
+            return;
+        };
+        let interval = now.saturating_duration_since(history.last).as_secs_f64() * 1000.0;
+        history.last = now;
+        history.intervals.push_back(interval);
+        history.sum += interval;
+        history.squared_sum += interval * interval;
+        if history.intervals.len() > self.window {
+            let oldest = history.intervals.pop_front().unwrap();
+            history.sum -= oldest;
+            history.squared_sum -= oldest * oldest;
+        }
+    }
+
+    pub fn phi(&self, peer: &str) -> f64 {
+        self.phi_at(peer, Instant::now())
+    }
+
+    /// Suspicion level of `peer`. Peers that haven't been heard from at least
+    /// twice have no history to judge by and are never suspected.
+    pub fn phi_at(&self, peer: &str, now: Instant) -> f64 {
+        let Some(history) = self.peers.get(peer) else {
+            return 0.0;
+        };
+        if history.intervals.is_empty() {
+            return 0.0;
+        }
+        let n = history.intervals.len() as f64;
+        let mean = history.sum / n + self.acceptable_pause.as_secs_f64() * 1000.0;
+        let variance = (history.squared_sum / n - (history.sum / n).powi(2)).max(0.0);
+        let std_dev = variance.sqrt().max(self.min_std_dev.as_secs_f64() * 1000.0);
+        let elapsed = now.saturating_duration_since(history.last).as_secs_f64() * 1000.0;
+
+        // logistic approximation of the normal CDF
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+
+    pub fn is_available(&self, peer: &str) -> bool {
+        self.phi(peer) < self.threshold
+    }
+
+    /// Suspicion level of every peer heard from so far.
+    pub fn suspicion(&self) -> HashMap<String, f64> {
+        let now = Instant::now();
+        self.peers
+            .keys()
+            .map(|peer| (peer.clone(), self.phi_at(peer, now)))
+            .collect()
+    }
+}
+
+/// A `FailureDetector` shared between `main_loop`, which feeds it every message
+/// it reads, and the node, which gets a handle through `Init::liveness`.
+#[derive(Debug, Clone, Default)]
+pub struct Liveness(Arc<Mutex<FailureDetector>>);
+
+impl Liveness {
+    pub fn new(detector: FailureDetector) -> Self {
+        Liveness(Arc::new(Mutex::new(detector)))
+    }
+
+    pub fn heartbeat(&self, peer: &str) {
+        self.0.lock().unwrap().heartbeat(peer);
+    }
+
+    pub fn phi(&self, peer: &str) -> f64 {
+        self.0.lock().unwrap().phi(peer)
+    }
+
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        !self.0.lock().unwrap().is_available(peer)
+    }
+
+    pub fn suspicion(&self) -> HashMap<String, f64> {
+        self.0.lock().unwrap().suspicion()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_heartbeats(detector: &mut FailureDetector, start: Instant, n: u64) -> Instant {
+        let mut now = start;
+        for _ in 0..n {
+            detector.heartbeat_at("n1", now);
+            now += Duration::from_millis(100);
+        }
+        now - Duration::from_millis(100)
+    }
+
+    #[test]
+    fn regular_heartbeats_keep_phi_low() {
+        let mut detector = FailureDetector::new(8.0);
+        let last = with_heartbeats(&mut detector, Instant::now(), 20);
+        for ms in [0, 50, 100, 150] {
+            let phi = detector.phi_at("n1", last + Duration::from_millis(ms));
+            assert!(phi < 1.0, "phi {phi} after {ms}ms");
+        }
+    }
+
+    #[test]
+    fn long_silence_crosses_the_threshold() {
+        let mut detector = FailureDetector::new(8.0);
+        let last = with_heartbeats(&mut detector, Instant::now(), 20);
+        let late = detector.phi_at("n1", last + Duration::from_millis(300));
+        let silent = detector.phi_at("n1", last + Duration::from_millis(1000));
+        assert!(late < silent, "phi must grow with the silence");
+        assert!(silent >= 8.0, "phi {silent} after ten intervals of silence");
+    }
+
+    #[test]
+    fn a_peer_heard_once_is_never_suspected() {
+        let mut detector = FailureDetector::new(8.0);
+        let start = Instant::now();
+        detector.heartbeat_at("n1", start);
+        assert_eq!(detector.phi_at("n1", start + Duration::from_secs(60)), 0.0);
+        assert_eq!(detector.phi_at("n2", start), 0.0);
+    }
+}
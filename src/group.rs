@@ -0,0 +1,111 @@
+//! Kafka-style consumer groups: members subscribe to keys, and the group
+//! spreads the subscribed keys over its live members so every key is consumed
+//! by exactly one of them. Members that stop heartbeating are dropped after
+//! the session timeout, and every change of membership bumps the generation.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::env_or;
+
+/// Membership and assignment of one group. Plain data, so nodes sharing a
+/// group can keep it in a KV store and update it with compare-and-swap.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsumerGroup {
+    generation: u64,
+    members: BTreeMap<String, Member>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Member {
+    subscription: BTreeSet<String>,
+    /// Unix millis of the last join or heartbeat.
+    last_seen: u64,
+    assigned: Vec<String>,
+}
+
+/// How long a member may go without a heartbeat, from
+/// `KAFKA_GROUP_SESSION_MS`.
+pub fn session_timeout() -> Duration {
+    Duration::from_millis(env_or("KAFKA_GROUP_SESSION_MS", 5000))
+}
+
+impl ConsumerGroup {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Keys assigned to `member`, `None` if it isn't in the group.
+    pub fn assignment(&self, member: &str) -> Option<&[String]> {
+        self.members.get(member).map(|m| m.assigned.as_slice())
+    }
+
+    /// Add `member` subscribed to `keys`, or change its subscription.
+    /// Rebalances if that changed anything.
+    pub fn join(&mut self, member: &str, keys: impl IntoIterator<Item = String>, now: u64) {
+        let subscription: BTreeSet<_> = keys.into_iter().collect();
+        let changed = self
+            .members
+            .get(member)
+            .is_none_or(|m| m.subscription != subscription);
+        let entry = self.members.entry(member.to_string()).or_default();
+        entry.subscription = subscription;
+        entry.last_seen = now;
+        if changed {
+            self.rebalance();
+        }
+    }
+
+    /// Returns false if `member` isn't in the group (anymore) and has to join.
+    pub fn heartbeat(&mut self, member: &str, now: u64) -> bool {
+        match self.members.get_mut(member) {
+            Some(m) => {
+                m.last_seen = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn leave(&mut self, member: &str) {
+        if self.members.remove(member).is_some() {
+            self.rebalance();
+        }
+    }
+
+    /// Drop the members not seen within `session` and rebalance.
+    pub fn expire(&mut self, now: u64, session: Duration) {
+        let cutoff = now.saturating_sub(session.as_millis() as u64);
+        let before = self.members.len();
+        self.members.retain(|_, m| m.last_seen >= cutoff);
+        if self.members.len() != before {
+            self.rebalance();
+        }
+    }
+
+    // 每个key给订阅了它、当前分到最少的成员；一样多时取名字最小的，各节点算出来一样
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        let keys: BTreeSet<String> = self
+            .members
+            .values()
+            .flat_map(|m| m.subscription.iter().cloned())
+            .collect();
+        for member in self.members.values_mut() {
+            member.assigned.clear();
+        }
+        for key in keys {
+            let owner = self
+                .members
+                .iter_mut()
+                .filter(|(_, m)| m.subscription.contains(&key))
+                .min_by_key(|(_, m)| m.assigned.len())
+                .map(|(_, m)| m);
+            if let Some(owner) = owner {
+                owner.assigned.push(key);
+            }
+        }
+    }
+}
@@ -5,6 +5,16 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub mod crdt;
+pub mod failure_detector;
+pub mod group;
+pub mod retry;
+
+pub use crdt::{Crdt, Gossiper};
+pub use failure_detector::{FailureDetector, Liveness};
+pub use group::ConsumerGroup;
+pub use retry::Retry;
+
 pub type Result<T> = std::result::Result<T, GanError>;
 
 pub fn main_loop<S, N, P, I>(init_state: S) -> Result<()>
@@ -20,9 +30,11 @@ where
 
     let init_msg: Message<InitPayload> =
         serde_json::from_str(&stdin.next().expect("no init message received")?)?;
-    let InitPayload::Init(init) = init_msg.body.payload else {
+    let InitPayload::Init(mut init) = init_msg.body.payload else {
         panic!("first message should be init");
     };
+    init.liveness = Liveness::new(FailureDetector::from_env());
+    let liveness = init.liveness.clone();
     let reply = Message {
         src: init_msg.dst,
         dst: init_msg.src,
@@ -43,7 +55,8 @@ where
         let stdin = std::io::stdin().lock();
         for input in stdin.lines() {
             let input: Message<P> = serde_json::from_str(&input?)?;
-            if let Err(_) = stdin_tx.send(Event::Message(input)) {
+            liveness.heartbeat(&input.src);
+            if stdin_tx.send(Event::Message(input)).is_err() {
                 return Ok::<_, GanError>(());
             }
         }
@@ -54,12 +67,55 @@ where
         let Ok(input) = rx.recv() else {
             break;
         };
+        let eof = matches!(input, Event::EOF);
         node.step(input, &mut stdout, &rx)?;
+        // 节点的定时线程(gossip、租约、retention)还拿着 tx，channel 永远不会断开，
+        // 读完输入就退出。之后还排着的注入事件直接丢掉：定时事件本来就是周期性的，
+        // 下次启动会重新开始；single-kafka 压缩好还没换进去的段丢掉也没关系，原来的段
+        // 还在，下次打开时会删掉剩下的 .log.compacted
+        if eof {
+            break;
+        }
     }
     let _ = handle.join().expect("stdin thread panicked");
     Ok(())
 }
 
+/// Read a tuning knob from the environment, falling back to `default` when the
+/// variable is unset or can't be parsed.
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Replace `path` with `bytes` so that a crash leaves either the old or the new
+/// contents on disk, never a mix.
+pub fn write_atomic(path: &std::path::Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    // rename 要等目录落盘才算数，不然断电后可能还是旧文件
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Milliseconds since the unix epoch. Every node of a maelstrom run shares the
+/// host clock, so timestamps taken on different nodes are comparable.
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 pub trait Node<S, Payload, InjectedPayload = ()> {
     fn from_init(
         init_state: S,
@@ -200,6 +256,9 @@ pub struct Body<Payload> {
 pub struct Init {
     pub node_id: String,
     pub node_ids: Vec<String>,
+    /// Suspicion levels of every peer, fed by every message `main_loop` reads.
+    #[serde(skip)]
+    pub liveness: Liveness,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -228,6 +287,11 @@ pub enum GanError {
     PreconditionFailed,
     #[error("key not exist")]
     KeyNotExist,
+    #[error("gave up after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        source: Box<GanError>,
+    },
 }
 
 impl<T> From<std::sync::mpsc::SendError<T>> for GanError {
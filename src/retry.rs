@@ -0,0 +1,163 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::{env_or, GanError, Result};
+
+/// Retry policy for operations that fail transiently, e.g. a CAS that lost a
+/// race with another node. Attempts are spaced by exponential backoff with
+/// full jitter and stop at `max_attempts` or once `deadline` has passed,
+/// whichever comes first.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    max_attempts: u32,
+    deadline: Option<Duration>,
+    base_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_attempts: 10,
+            deadline: None,
+            base_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+impl Retry {
+    /// Policy from `RETRY_MAX_ATTEMPTS`, `RETRY_DEADLINE_MS`, `RETRY_BASE_MS`
+    /// and `RETRY_MAX_BACKOFF_MS`, with the defaults for the unset ones.
+    pub fn from_env() -> Self {
+        let default = Retry::default();
+        let mut retry = Retry {
+            max_attempts: env_or("RETRY_MAX_ATTEMPTS", default.max_attempts),
+            deadline: None,
+            base_backoff: Duration::from_millis(env_or("RETRY_BASE_MS", 5)),
+            max_backoff: Duration::from_millis(env_or("RETRY_MAX_BACKOFF_MS", 200)),
+        };
+        let deadline = env_or("RETRY_DEADLINE_MS", 0);
+        if deadline > 0 {
+            retry = retry.deadline(Duration::from_millis(deadline));
+        }
+        retry
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Don't start another attempt once `deadline` has passed since the first.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Backoff before the second attempt; it doubles after every attempt up to
+    /// `max_backoff`.
+    pub fn backoff(mut self, base_backoff: Duration, max_backoff: Duration) -> Self {
+        self.base_backoff = base_backoff;
+        self.max_backoff = max_backoff.max(base_backoff);
+        self
+    }
+
+    /// Run `op` until it succeeds, fails with an error `retryable` rejects, or
+    /// the budget runs out, in which case the last error is returned wrapped
+    /// in `GanError::RetriesExhausted`. `op` gets the 0-based attempt number.
+    pub fn run<T>(
+        &self,
+        mut op: impl FnMut(u32) -> Result<T>,
+        retryable: impl Fn(&GanError) -> bool,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut attempt = 0;
+        loop {
+            let err = match op(attempt) {
+                Ok(value) => return Ok(value),
+                Err(err) if retryable(&err) => err,
+                Err(err) => return Err(err),
+            };
+            attempt += 1;
+            let backoff = self
+                .base_backoff
+                .saturating_mul(1 << (attempt - 1).min(16))
+                .min(self.max_backoff);
+            let backoff = rng.gen_range(Duration::ZERO..=backoff);
+            let out_of_time = self
+                .deadline
+                .is_some_and(|deadline| start.elapsed() + backoff >= deadline);
+            if attempt >= self.max_attempts || out_of_time {
+                return Err(GanError::RetriesExhausted {
+                    attempts: attempt,
+                    source: Box::new(err),
+                });
+            }
+            std::thread::sleep(backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_backoff() -> Retry {
+        Retry::default().backoff(Duration::ZERO, Duration::ZERO)
+    }
+
+    #[test]
+    fn succeeds_after_retryable_failures() {
+        let mut calls = Vec::new();
+        let result = no_backoff().max_attempts(5).run(
+            |attempt| {
+                calls.push(attempt);
+                if attempt < 2 {
+                    Err(GanError::PreconditionFailed)
+                } else {
+                    Ok(attempt)
+                }
+            },
+            |e| matches!(e, GanError::PreconditionFailed),
+        );
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(calls, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn max_attempts_caps_the_calls() {
+        let mut calls = 0;
+        let result: Result<()> = no_backoff().max_attempts(3).run(
+            |_| {
+                calls += 1;
+                Err(GanError::PreconditionFailed)
+            },
+            |_| true,
+        );
+        assert_eq!(calls, 3);
+        match result {
+            Err(GanError::RetriesExhausted { attempts, source }) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*source, GanError::PreconditionFailed));
+            }
+            other => panic!("expected RetriesExhausted, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn non_retryable_errors_return_immediately() {
+        let mut calls = 0;
+        let result: Result<()> = no_backoff().max_attempts(10).run(
+            |_| {
+                calls += 1;
+                Err(GanError::KeyNotExist)
+            },
+            |e| matches!(e, GanError::PreconditionFailed),
+        );
+        assert_eq!(calls, 1);
+        assert!(matches!(result, Err(GanError::KeyNotExist)));
+    }
+}